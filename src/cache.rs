@@ -0,0 +1,127 @@
+use anyhow::Context;
+use anyhow::Result;
+use chrono::prelude::*;
+use futures::lock::Mutex as AsyncMutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Names and images of the containers of a task definition, in their order
+pub type ContainerImages = Vec<(String, String)>;
+
+/// What the scan and the history need of a task definition revision
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskDefinitionRevision {
+    pub container_images: ContainerImages,
+    pub registered_at: Option<DateTime<Utc>>,
+}
+
+/// Lookup of a task definition, shared by the services using it
+type Slot = Arc<AsyncMutex<Option<TaskDefinitionRevision>>>;
+
+fn cache_path() -> Result<PathBuf> {
+    let home_dir = dirs::home_dir().context("Missing home directory")?;
+    Ok(Path::new(home_dir.as_path()).join(".awsManager-task-definitions.json"))
+}
+
+/// Arn of a task definition revision, a family alone can point to another revision later
+fn is_revision_arn(arn: &str) -> bool {
+    arn.starts_with("arn:")
+        && arn.contains(":task-definition/")
+        && arn
+            .rsplit(':')
+            .next()
+            .map_or(false, |revision| revision.parse::<u32>().is_ok())
+}
+
+/// Task definition revisions by arn, each revision is only described once
+/// as they never change, the cache is kept on disk between the runs
+#[derive(Default)]
+pub struct TaskDefinitionCache {
+    slots: Mutex<HashMap<String, Slot>>,
+    on_disk: bool,
+}
+
+impl TaskDefinitionCache {
+    /// Cache kept in memory for this run only
+    pub fn in_memory() -> TaskDefinitionCache {
+        TaskDefinitionCache::default()
+    }
+
+    /// Cache loaded from disk, empty when missing or unreadable, like the caches
+    /// written before the registration dates were kept
+    pub fn load() -> TaskDefinitionCache {
+        let revisions: HashMap<String, TaskDefinitionRevision> =
+            match cache_path().and_then(|path| {
+                let f = File::open(path)?;
+                Ok(serde_json::from_reader(BufReader::new(f))?)
+            }) {
+                Ok(revisions) => revisions,
+                Err(e) => {
+                    debug!("Not using the task definition cache: {:#}", e);
+                    HashMap::new()
+                }
+            };
+        debug!("Loaded {} cached task definitions", revisions.len());
+        TaskDefinitionCache {
+            slots: Mutex::new(
+                revisions
+                    .into_iter()
+                    .map(|(arn, revision)| (arn, Arc::new(AsyncMutex::new(Some(revision)))))
+                    .collect(),
+            ),
+            on_disk: true,
+        }
+    }
+
+    /// Revision of the task definition, fetched only when no other lookup of the same arn
+    /// already did it
+    pub async fn get_or_fetch<F>(&self, arn: &str, fetch: F) -> Result<TaskDefinitionRevision>
+    where
+        F: Future<Output = Result<TaskDefinitionRevision>>,
+    {
+        if !is_revision_arn(arn) {
+            return fetch.await;
+        }
+        let slot = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(arn.to_owned())
+            .or_insert_with(|| Arc::new(AsyncMutex::new(None)))
+            .clone();
+        let mut cached = slot.lock().await;
+        if let Some(revision) = cached.as_ref() {
+            return Ok(revision.clone());
+        }
+        let revision = fetch.await?;
+        *cached = Some(revision.clone());
+        Ok(revision)
+    }
+
+    /// Writes the known revisions to disk, for the next runs
+    pub fn persist(&self) -> Result<()> {
+        if !self.on_disk {
+            return Ok(());
+        }
+        let revisions: HashMap<String, TaskDefinitionRevision> = self
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(arn, slot)| {
+                let cached = slot.try_lock()?;
+                cached
+                    .as_ref()
+                    .map(|revision| (arn.clone(), revision.clone()))
+            })
+            .collect();
+        let f = File::create(cache_path()?)?;
+        serde_json::to_writer(f, &revisions)?;
+        Ok(())
+    }
+}
@@ -1,35 +1,656 @@
+use crate::config::Config;
+use crate::errors::ErrorKind;
+use crate::pac::{Pac, PacRoute};
+use crate::recording::{Mode, Recorder, Replayer};
+use crate::retry::{RetryPolicy, RetryReason, RetryStats};
+use crate::socks::{SocksConnector, SocksProxy, SocksRoute};
+use crate::stats::CallStats;
+use crate::tls;
+use anyhow::Context;
 use anyhow::Result;
-use hyper::{Client, Uri};
+use console::style;
+use hyper::service::Service;
+use hyper::{Client, StatusCode, Uri};
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
 use hyper_tls::HttpsConnector;
+use rusoto_core::request::{
+    DispatchSignedRequest, DispatchSignedRequestFuture, HttpDispatchError, HttpResponse,
+};
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_core::ByteStream;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use typed_headers::Credentials;
 
-type HttpConnector =
-    hyper_proxy::ProxyConnector<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
+type HttpConnector = hyper_proxy::ProxyConnector<hyper_tls::HttpsConnector<SocksConnector>>;
 
-pub type HttpClient = rusoto_core::HttpClient<HttpConnector>;
+/// Idle connections are dropped before the proxies and AWS endpoints close them
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const POOL_MAX_IDLE_PER_HOST: usize = 16;
 
-pub fn new_client() -> Result<HttpClient> {
-    let connector = HttpsConnector::new();
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
 
-    let http_connector: HttpConnector;
-    if let Ok(proxy_url) = std::env::var("http_proxy") {
-        let proxy = Proxy::new(Intercept::All, proxy_url.parse::<Uri>()?);
-        http_connector = ProxyConnector::from_proxy(connector, proxy)?;
+/// Operations that can be sent again without side effects, GetSessionToken is not
+/// one of them as the mfa code is consumed by the first request
+const IDEMPOTENT_OPERATION_PREFIXES: &[&str] =
+    &["List", "Describe", "GetCallerIdentity", "AssumeRole"];
+
+/// Rusoto http client sending again, with backoff, the throttled requests and the
+/// idempotent ones that failed on server or network errors, like connections of
+/// the pool closed by the other side
+pub struct HttpClient {
+    inner: Arc<rusoto_core::HttpClient<HttpConnector>>,
+    retry_policy: RetryPolicy,
+    retry_stats: Arc<RetryStats>,
+    call_stats: Arc<CallStats>,
+    request_timeout: Duration,
+    recorder: Option<Arc<Recorder>>,
+    replayer: Option<Arc<Replayer>>,
+    pac: Option<Arc<Pac>>,
+}
+
+impl HttpClient {
+    pub fn retry_summary(&self) -> Option<String> {
+        self.retry_stats.summary()
+    }
+
+    pub fn call_stats_report(&self) -> Vec<String> {
+        self.call_stats.report()
+    }
+}
+
+/// Name of the operation, from the target header of json apis or the action of query apis
+fn operation_name(request: &SignedRequest) -> Option<String> {
+    if let Some(target) = request
+        .headers
+        .get("x-amz-target")
+        .and_then(|values| values.first())
+    {
+        let target = String::from_utf8_lossy(target);
+        return target.rsplit('.').next().map(|op| op.to_owned());
+    }
+    if let Some(Some(action)) = request.params.get("Action") {
+        return Some(action.clone());
+    }
+    match &request.payload {
+        Some(SignedRequestPayload::Buffer(body)) => String::from_utf8_lossy(body)
+            .split('&')
+            .find(|kv| kv.starts_with("Action="))
+            .map(|kv| kv["Action=".len()..].to_owned()),
+        _ => None,
+    }
+}
+
+fn is_idempotent(request: &SignedRequest) -> bool {
+    operation_name(request).map_or(false, |op| {
+        IDEMPOTENT_OPERATION_PREFIXES
+            .iter()
+            .any(|prefix| op.starts_with(prefix))
+    })
+}
+
+/// Copy of an already signed request, only possible for buffered payloads
+fn copy_request(request: &SignedRequest) -> Option<SignedRequest> {
+    let payload = match &request.payload {
+        Some(SignedRequestPayload::Buffer(body)) => {
+            Some(SignedRequestPayload::Buffer(body.clone()))
+        }
+        Some(SignedRequestPayload::Stream(_)) => return None,
+        None => None,
+    };
+    let mut copy = SignedRequest::new(
+        &request.method,
+        &request.service,
+        &request.region,
+        &request.path,
+    );
+    copy.headers = request.headers.clone();
+    copy.params = request.params.clone();
+    copy.scheme = request.scheme.clone();
+    copy.hostname = request.hostname.clone();
+    copy.payload = payload;
+    copy.canonical_query_string = request.canonical_query_string.clone();
+    copy.canonical_uri = request.canonical_uri.clone();
+    Some(copy)
+}
+
+/// Reason to send the request again, the body of the failed responses is buffered
+/// to look for the throttling error codes
+async fn retry_reason(
+    res: Result<HttpResponse, HttpDispatchError>,
+    idempotent: bool,
+) -> (Result<HttpResponse, HttpDispatchError>, Option<RetryReason>) {
+    let network_reason = if idempotent {
+        Some(RetryReason::Network)
     } else {
-        http_connector = ProxyConnector::new(connector)?;
+        None
+    };
+    let response = match res {
+        Ok(response) if !response.status.is_success() => response,
+        Err(e) => return (Err(e), network_reason),
+        ok => return (ok, None),
+    };
+    let buffered = match response.buffer().await {
+        Ok(buffered) => buffered,
+        Err(e) => return (Err(e), network_reason),
+    };
+
+    let reason = if buffered.status == StatusCode::TOO_MANY_REQUESTS
+        || ErrorKind::of_message(&String::from_utf8_lossy(&buffered.body)) == ErrorKind::Throttling
+    {
+        // throttled requests were not processed, even the non idempotent ones can be sent again
+        Some(RetryReason::Throttling)
+    } else if buffered.status.is_server_error() && idempotent {
+        Some(RetryReason::ServerError)
+    } else {
+        None
+    };
+    let response = HttpResponse {
+        status: buffered.status,
+        body: ByteStream::from(buffered.body.to_vec()),
+        headers: buffered.headers,
+    };
+    (Ok(response), reason)
+}
+
+impl DispatchSignedRequest for HttpClient {
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        if let Some(replayer) = &self.replayer {
+            let res = replayer.answer(&request, operation_name(&request));
+            return Box::pin(async move { res });
+        }
+        let recorded_request = self.recorder.as_ref().map(|recorder| {
+            (
+                recorder.clone(),
+                recorder.request(&request, operation_name(&request)),
+            )
+        });
+
+        let call_stats = self.call_stats.clone();
+        let service = request.service.clone();
+        let operation = operation_name(&request).unwrap_or_else(|| "unknown".to_owned());
+        let bytes_sent = match &request.payload {
+            Some(SignedRequestPayload::Buffer(body)) => body.len() as u64,
+            _ => 0,
+        };
+        let started = Instant::now();
+
+        let idempotent = is_idempotent(&request);
+        let template = copy_request(&request);
+        let inner = self.inner.clone();
+        let retry_policy = self.retry_policy.clone();
+        let retry_stats = self.retry_stats.clone();
+        // rusoto clients do not set any timeout, a stuck connection would hang forever
+        let timeout = timeout.or(Some(self.request_timeout));
+        let pac = self.pac.clone();
+        let host = request.hostname();
+        let first_dispatch = inner.dispatch(request, timeout);
+
+        let retried: DispatchSignedRequestFuture = Box::pin(async move {
+            if let Some(pac) = pac {
+                pac.prepare(host).await;
+            }
+            let mut res = first_dispatch.await;
+            let mut attempt = 1;
+            loop {
+                let (checked_res, reason) = retry_reason(res, idempotent).await;
+                let (retry_template, reason) = match (&template, reason) {
+                    (Some(retry_template), Some(reason)) if attempt < retry_policy.max_attempts => {
+                        (retry_template, reason)
+                    }
+                    _ => return checked_res,
+                };
+                retry_stats.record(reason);
+                let delay = retry_policy.backoff(attempt);
+                debug!(
+                    "Sending {:?} again in {:?} after {:?}",
+                    operation_name(retry_template),
+                    delay,
+                    reason
+                );
+                tokio::time::delay_for(delay).await;
+
+                attempt += 1;
+                res = match copy_request(retry_template) {
+                    Some(request) => inner.dispatch(request, timeout).await,
+                    None => return checked_res,
+                };
+            }
+        });
+
+        let recorded: DispatchSignedRequestFuture = match recorded_request {
+            Some((recorder, request)) => {
+                Box::pin(async move { recorder.save(request, retried.await).await })
+            }
+            None => retried,
+        };
+
+        Box::pin(async move {
+            let res = recorded.await;
+            let bytes_received = res.as_ref().ok().map_or(0, |response| {
+                response
+                    .headers
+                    .get("content-length")
+                    .and_then(|length| length.parse::<u64>().ok())
+                    .unwrap_or(0)
+            });
+            call_stats.record(
+                &service,
+                &operation,
+                started.elapsed(),
+                bytes_sent,
+                bytes_received,
+            );
+            res
+        })
+    }
+}
+
+/// First value set among the given environment variables
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|v| !v.trim().is_empty())
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn ip_in_cidr(ip: &IpAddr, cidr: &str) -> bool {
+    let mut parts = cidr.splitn(2, '/');
+    let network = match parts.next().and_then(|n| n.parse::<IpAddr>().ok()) {
+        Some(network) => network,
+        None => return false,
+    };
+    let prefix_len = parts.next().and_then(|l| l.parse::<u32>().ok());
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let prefix_len = prefix_len.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let prefix_len = prefix_len.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            u128::from(*ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Host of a no_proxy entry, the ports are ignored and the entry matches all of them
+fn strip_port(entry: &str) -> &str {
+    if let Some(bracketed) = entry.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or("");
+    }
+    match entry.rsplitn(2, ':').collect::<Vec<&str>>().as_slice() {
+        // a bare IPv6 address holds several colons
+        [port, host] if !host.contains(':') && port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => entry,
+    }
+}
+
+/// Hosts bypassing the proxy, as given by the no_proxy environment variable:
+/// domain suffixes, ip addresses or CIDR blocks matched against ip hosts, or * for all
+#[derive(Debug, Default)]
+struct NoProxy {
+    entries: Vec<String>,
+}
+
+impl NoProxy {
+    fn parse(value: &str) -> NoProxy {
+        NoProxy {
+            entries: value
+                .split(',')
+                .map(|e| strip_port(e.trim()).to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect(),
+        }
+    }
+
+    fn from_env() -> NoProxy {
+        env_var(&["no_proxy", "NO_PROXY"])
+            .map(|v| NoProxy::parse(&v))
+            .unwrap_or_default()
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase();
+        let host_ip = host.parse::<IpAddr>().ok();
+        self.entries.iter().any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            if let Some(ip) = &host_ip {
+                return ip_in_cidr(ip, entry);
+            }
+            let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+            host == domain || host.ends_with(&format!(".{}", domain))
+        })
+    }
+}
+
+fn build_proxy(proxy_url: &str, scheme: &'static str, no_proxy: Arc<NoProxy>) -> Result<Proxy> {
+    let uri = proxy_url
+        .parse::<Uri>()
+        .with_context(|| format!("Invalid proxy url {}", proxy_url))?;
+
+    let intercept = move |s: Option<&str>, host: Option<&str>, _port: Option<u16>| {
+        s == Some(scheme) && !host.map_or(false, |h| no_proxy.matches(h))
+    };
+    let mut proxy = Proxy::new(Intercept::Custom(intercept.into()), uri.clone());
+
+    let userinfo = uri
+        .authority()
+        .and_then(|a| a.as_str().rsplitn(2, '@').nth(1).map(|u| u.to_owned()));
+    if let Some(userinfo) = userinfo {
+        let mut user_password = userinfo.splitn(2, ':');
+        let user = percent_decode(user_password.next().unwrap_or(""));
+        let password = percent_decode(user_password.next().unwrap_or(""));
+        proxy.set_authorization(
+            Credentials::basic(&user, &password)
+                .map_err(|_| anyhow!("Invalid proxy credentials in {}", scheme))?,
+        );
+    }
+    Ok(proxy)
+}
+
+/// Http proxy used for the hosts the PAC file sends to it
+fn build_pac_proxy(host_port: &str, pac: Arc<Pac>) -> Result<Proxy> {
+    let uri = format!("http://{}", host_port)
+        .parse::<Uri>()
+        .with_context(|| format!("Invalid proxy {} in the PAC file", host_port))?;
+    let route = PacRoute::Proxy(host_port.to_owned());
+    let intercept = move |_: Option<&str>, host: Option<&str>, _port: Option<u16>| {
+        host.map_or(false, |h| pac.route(h) == route)
+    };
+    Ok(Proxy::new(Intercept::Custom(intercept.into()), uri))
+}
+
+fn is_socks_url(url: &str) -> bool {
+    url.starts_with("socks5://") || url.starts_with("socks5h://")
+}
+
+fn https_proxy_url(proxy_override: Option<&String>) -> Option<String> {
+    proxy_override
+        .cloned()
+        .or_else(|| env_var(&["https_proxy", "HTTPS_PROXY", "http_proxy", "HTTP_PROXY"]))
+}
+
+/// Tcp connections and http proxies chosen by the configuration, shared by the
+/// http client and the TLS diagnostic
+struct ProxySettings {
+    socks_connector: SocksConnector,
+    proxies: Vec<Proxy>,
+    pac: Option<Arc<Pac>>,
+    connect_timeout: Duration,
+}
+
+/// Proxies given explicitly, the ones of the PAC file or the ones of the https_proxy,
+/// http_proxy and no_proxy environment variables
+fn proxy_settings(proxy_override: Option<&String>, config: &Config) -> Result<ProxySettings> {
+    let connect_timeout = Duration::from_secs(
+        config
+            .connect_timeout_secs
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+    );
+    let mut http = hyper::client::HttpConnector::new();
+    http.set_connect_timeout(Some(connect_timeout));
+    http.enforce_http(false);
+
+    // an explicit proxy takes precedence over the PAC file, which replaces the environment
+    let pac = match (&config.pac_file, proxy_override) {
+        (Some(path), None) => match Pac::load(path) {
+            Ok(pac) => Some(Arc::new(pac)),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    style(format!(
+                        "Using the proxy environment variables instead: {:#}",
+                        e
+                    ))
+                    .yellow()
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+    let (https_proxy_url, http_proxy_url) = match &pac {
+        Some(_) => (None, None),
+        None => (
+            https_proxy_url(proxy_override),
+            proxy_override
+                .cloned()
+                .or_else(|| env_var(&["http_proxy", "HTTP_PROXY"])),
+        ),
+    };
+    let no_proxy = Arc::new(NoProxy::from_env());
+    // the proxy urls are not logged as they may hold credentials
+    debug!(
+        "Using https proxy: {}, http proxy: {}, PAC file: {:?}, no proxy {:?}",
+        https_proxy_url.is_some(),
+        http_proxy_url.is_some(),
+        config.pac_file.as_ref().filter(|_| pac.is_some()),
+        no_proxy
+    );
+
+    let socks_proxy = match &https_proxy_url {
+        Some(proxy_url) => SocksProxy::from_url(proxy_url)?,
+        None => None,
+    };
+    let socks_route: SocksRoute = match (&pac, socks_proxy) {
+        (Some(pac), _) => {
+            let pac = pac.clone();
+            Arc::new(move |host| match pac.route(host) {
+                PacRoute::Socks(host_port) => SocksProxy::from_host_port(&host_port),
+                _ => None,
+            })
+        }
+        (None, Some(socks_proxy)) => {
+            let no_proxy = no_proxy.clone();
+            Arc::new(move |host| {
+                if no_proxy.matches(host) {
+                    None
+                } else {
+                    Some(socks_proxy.clone())
+                }
+            })
+        }
+        (None, None) => Arc::new(|_| None),
+    };
+
+    let mut proxies = Vec::new();
+    if let Some(pac) = &pac {
+        for host_port in pac.http_proxies() {
+            proxies.push(build_pac_proxy(&host_port, pac.clone())?);
+        }
+    }
+    if let Some(proxy_url) = https_proxy_url.filter(|url| !is_socks_url(url)) {
+        proxies.push(build_proxy(&proxy_url, "https", no_proxy.clone())?);
+    }
+    if let Some(proxy_url) = http_proxy_url.filter(|url| !is_socks_url(url)) {
+        proxies.push(build_proxy(&proxy_url, "http", no_proxy)?);
+    }
+    Ok(ProxySettings {
+        socks_connector: SocksConnector::new(http, socks_route, connect_timeout),
+        proxies,
+        pac,
+        connect_timeout,
+    })
+}
+
+/// Certificates presented for the port 443 of the host, reached through the same proxies
+/// as the http client
+pub async fn fetch_certificate_chain(
+    host: &str,
+    proxy_override: Option<&String>,
+    config: &Config,
+) -> Result<Vec<Vec<u8>>> {
+    let settings = proxy_settings(proxy_override, config)?;
+    if let Some(pac) = settings.pac {
+        pac.prepare(host.to_owned()).await;
+    }
+    let mut connector = ProxyConnector::unsecured(settings.socks_connector);
+    for proxy in settings.proxies {
+        connector.add_proxy(proxy);
+    }
+    let uri = format!("https://{}:443", host)
+        .parse::<Uri>()
+        .with_context(|| format!("Invalid host {}", host))?;
+    // the proxies may accept the connection and never answer
+    tokio::time::timeout(settings.connect_timeout, async {
+        let mut stream = connector.call(uri).await?;
+        tls::read_certificate_chain(&mut stream, host).await
+    })
+    .await
+    .map_err(|_| anyhow!("Timed out getting the certificates of {}", host))?
+}
+
+/// Builds the http client, going through the proxies chosen by the configuration,
+/// the exchanges are saved or answered from a directory in the recording modes
+pub fn new_client(
+    proxy_override: Option<&String>,
+    recording: Option<&Mode>,
+    config: &Config,
+) -> Result<HttpClient> {
+    // the same TLS settings are used for direct connections and for the proxy tunnels
+    let tls_connector = tls::new_tls_connector(&config.ca_bundles)?;
+    let settings = proxy_settings(proxy_override, config)?;
+    let connector = HttpsConnector::from((settings.socks_connector, tls_connector.clone().into()));
+
+    let mut http_connector: HttpConnector = ProxyConnector::unsecured(connector);
+    http_connector.set_tls(Some(tls_connector));
+    for proxy in settings.proxies {
+        http_connector.add_proxy(proxy);
     }
     let mut hyper_builder = Client::builder();
 
-    // disabling due to connection closed issue
-    hyper_builder.pool_max_idle_per_host(0);
-    /* still getting connection closed before message completed
-     * https://github.com/rusoto/rusoto/issues/1766
+    // the connections closed while idle in the pool are still reported as
+    // "connection closed before message completed", the dispatch retries them
+    // https://github.com/rusoto/rusoto/issues/1766
     hyper_builder
-        .pool_idle_timeout(Duration::from_secs(5))
-        .pool_max_idle_per_host(4)
-        .retry_canceled_requests(true);*/
-    Ok(rusoto_core::HttpClient::from_builder(
-        hyper_builder,
-        http_connector,
-    ))
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .retry_canceled_requests(true);
+    Ok(HttpClient {
+        inner: Arc::new(rusoto_core::HttpClient::from_builder(
+            hyper_builder,
+            http_connector,
+        )),
+        retry_policy: RetryPolicy::from_config(config),
+        request_timeout: Duration::from_secs(
+            config
+                .request_timeout_secs
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS),
+        ),
+        retry_stats: Arc::new(RetryStats::default()),
+        call_stats: Arc::new(CallStats::default()),
+        recorder: match recording {
+            Some(Mode::Record(dir)) => Some(Arc::new(Recorder::new(dir)?)),
+            _ => None,
+        },
+        replayer: match recording {
+            Some(Mode::Replay(dir)) => Some(Arc::new(Replayer::new(dir)?)),
+            _ => None,
+        },
+        pac: settings.pac,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_percent_escapes() {
+        assert_eq!(percent_decode("p%40ss%3Aword"), "p@ss:word");
+        assert_eq!(percent_decode("caf%C3%A9"), "caf\u{e9}");
+        // invalid or truncated escapes are kept as they are
+        assert_eq!(percent_decode("100%zz"), "100%zz");
+        assert_eq!(percent_decode("end%4"), "end%4");
+        assert_eq!(percent_decode("end%"), "end%");
+    }
+
+    #[test]
+    fn matches_the_cidr_blocks() {
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        assert!(ip_in_cidr(&ip, "10.0.0.0/8"));
+        assert!(!ip_in_cidr(&ip, "10.0.0.0/16"));
+        assert!(ip_in_cidr(&ip, "0.0.0.0/0"));
+        assert!(ip_in_cidr(&ip, "10.1.2.3/32"));
+        assert!(!ip_in_cidr(&ip, "10.1.2.4/32"));
+        assert!(ip_in_cidr(&ip, "10.1.2.3"));
+        assert!(!ip_in_cidr(&ip, "::/0"));
+        assert!(!ip_in_cidr(&ip, "not-an-ip/8"));
+
+        let ip: IpAddr = "fd00:ec2::254".parse().unwrap();
+        assert!(ip_in_cidr(&ip, "fd00::/8"));
+        assert!(ip_in_cidr(&ip, "::/0"));
+        assert!(ip_in_cidr(&ip, "fd00:ec2::254/128"));
+        assert!(!ip_in_cidr(&ip, "fd00:ec2::253"));
+        assert!(!ip_in_cidr(&ip, "0.0.0.0/0"));
+    }
+
+    #[test]
+    fn strips_the_ports_of_the_entries() {
+        assert_eq!(strip_port("example.com:8443"), "example.com");
+        assert_eq!(strip_port("10.0.0.1:80"), "10.0.0.1");
+        assert_eq!(strip_port("[::1]:8080"), "::1");
+        assert_eq!(strip_port("[fd00::1]"), "fd00::1");
+        assert_eq!(strip_port("::1"), "::1");
+        assert_eq!(strip_port("fd00::/8"), "fd00::/8");
+        assert_eq!(strip_port("example.com"), "example.com");
+    }
+
+    #[test]
+    fn matches_the_no_proxy_entries() {
+        let no_proxy = NoProxy::parse(
+            " .internal.example.com, *.corp.example.com,Example.org:443,10.0.0.0/8,[::1]:8080,fd00::/8,",
+        );
+        assert!(no_proxy.matches("a.internal.example.com"));
+        assert!(no_proxy.matches("internal.example.com"));
+        assert!(no_proxy.matches("b.corp.example.com"));
+        assert!(no_proxy.matches("corp.example.com"));
+        assert!(no_proxy.matches("www.example.org"));
+        assert!(no_proxy.matches("EXAMPLE.ORG"));
+        assert!(!no_proxy.matches("notexample.org"));
+        assert!(!no_proxy.matches("example.com"));
+        assert!(no_proxy.matches("10.20.30.40"));
+        assert!(!no_proxy.matches("11.0.0.1"));
+        assert!(no_proxy.matches("[::1]"));
+        assert!(no_proxy.matches("[fd00:ec2::254]"));
+        assert!(!no_proxy.matches("[fe80::1]"));
+        // the ip entries are not matched against host names
+        assert!(!no_proxy.matches("localhost"));
+
+        assert!(NoProxy::parse("*").matches("ecs.eu-west-1.amazonaws.com"));
+        assert!(!NoProxy::parse("").matches("ecs.eu-west-1.amazonaws.com"));
+    }
 }
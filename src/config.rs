@@ -9,21 +9,63 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Key marking the profiles written by the export command, they must not be
+/// taken as the source of the mfa session
+pub const EXPORTED_PROFILE_KEY: &str = "exported_by_aws_list_ecs_images";
+
+/// Keys and token of the replayed runs, the recorded responses don't depend on them
+const REPLAY_PLACEHOLDER: &str = "REPLAYED";
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct Config {
     pub aws_access_key_id: String,
     pub aws_secret_access_key: String,
+    pub aws_access_key_creation: Option<AccessKeyCreation>,
+    pub access_key_max_age_days: Option<i64>,
     pub aws_mfa_device_arn: Option<String>,
+    pub mfa_max_attempts: Option<u32>,
+    pub retry_max_attempts: Option<u32>,
+    pub concurrency: Option<usize>,
+    pub account_concurrency: Option<usize>,
+    pub connect_timeout_secs: Option<u64>,
+    pub request_timeout_secs: Option<u64>,
     pub aws_use_default_credentials: bool,
     pub region: Option<String>,
+    /// Endpoint used for all the services, LocalStack or a fake of aws
+    pub endpoint_url: Option<String>,
+    /// Endpoints by service name (sts, iam, ecs, events), taking precedence over endpoint_url
+    #[serde(default)]
+    pub endpoint_urls: HashMap<String, String>,
+    /// Endpoint given on the command line, never persisted
+    #[serde(skip)]
+    pub endpoint_url_override: Option<String>,
+    pub proxy: Option<String>,
+    pub pac_file: Option<String>,
+    #[serde(default)]
+    pub ca_bundles: Vec<String>,
     pub aws_sts_profile: Option<String>,
     pub aws_temp_access_key_id: Option<String>,
     pub aws_temp_secret_access_key: Option<String>,
     pub aws_session_token: Option<String>,
     pub aws_session_expiration: Option<DateTime<FixedOffset>>,
     pub roles: HashMap<String, String>,
+    #[serde(default)]
+    pub role_session_policies: HashMap<String, SessionPolicy>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessKeyCreation {
+    pub access_key_id: String,
+    pub create_date: DateTime<FixedOffset>,
+}
+
+/// Session policies restricting the permissions of an assumed role
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct SessionPolicy {
+    pub policy: Option<serde_json::Value>,
+    pub policy_arns: Option<Vec<String>>,
 }
 
 impl Config {
@@ -77,23 +119,44 @@ impl Config {
             set_roles(&mut config)?;
         }
 
-        if config.aws_sts_profile.is_none() && config.aws_mfa_device_arn.is_none() {
-            config.aws_mfa_device_arn = Some(
-                Input::<String>::new()
-                    .with_prompt("Enter your MFA device ARN")
-                    .validate_with(|input: &str| -> Result<(), &str> {
-                        if input.starts_with("arn") {
-                            Ok(())
-                        } else {
-                            Err("This is not a valid mfa arn")
-                        }
-                    })
-                    .interact()?,
-            );
-        }
         Ok(config)
     }
 
+    /// Configuration of a replayed run, nothing is read from the home directory and the
+    /// placeholder session is never checked against sts nor persisted
+    pub fn for_replay(region: Option<String>, roles: HashMap<String, String>) -> Config {
+        let placeholder = Some(REPLAY_PLACEHOLDER.to_owned());
+        Config {
+            aws_access_key_id: REPLAY_PLACEHOLDER.to_owned(),
+            aws_secret_access_key: REPLAY_PLACEHOLDER.to_owned(),
+            region,
+            aws_temp_access_key_id: placeholder.clone(),
+            aws_temp_secret_access_key: placeholder.clone(),
+            aws_session_token: placeholder,
+            aws_session_expiration: Some(
+                (Utc::now() + chrono::Duration::days(1)).with_timezone(&FixedOffset::east(0)),
+            ),
+            roles,
+            ..Default::default()
+        }
+    }
+
+    pub fn ask_mfa_device_arn(&mut self) -> Result<()> {
+        self.aws_mfa_device_arn = Some(
+            Input::<String>::new()
+                .with_prompt("Enter your MFA device ARN")
+                .validate_with(|input: &str| -> Result<(), &str> {
+                    if input.starts_with("arn") {
+                        Ok(())
+                    } else {
+                        Err("This is not a valid mfa arn")
+                    }
+                })
+                .interact()?,
+        );
+        Ok(())
+    }
+
     pub fn persist(&self) -> Result<()> {
         let home_dir = dirs::home_dir().context("Missing home directory")?;
         let config_path = Path::new(home_dir.as_path()).join(".awsManager.json");
@@ -102,13 +165,18 @@ impl Config {
         Ok(())
     }
 
+    /// True when the session token comes from .aws/credentials without any expiration hint
+    pub fn needs_token_check(&self) -> bool {
+        self.aws_sts_profile.is_some() && self.aws_session_expiration.is_none()
+    }
+
     pub fn is_token_valid(&self) -> bool {
         if self.aws_session_token.is_none() {
             return false;
         }
 
-        if self.aws_sts_profile.is_some() {
-            // rely completely on the token from .aws/credentials
+        if self.needs_token_check() {
+            // no expiration hint in .aws/credentials, the token must be checked against sts
             return true;
         }
 
@@ -138,15 +206,89 @@ fn set_roles(cfg: &mut Config) -> Result<()> {
     Ok(())
 }
 
-fn set_default_aws_credentials(cfg: &mut Config) -> Result<()> {
+pub fn aws_credentials_path() -> Result<PathBuf> {
     let home_dir = dirs::home_dir().context("Missing home directory")?;
-    let mut p = Path::new(home_dir.as_path()).join(".aws/credentials");
+    let p = Path::new(home_dir.as_path()).join(".aws/credentials");
     if !p.exists() {
         let config_dir = dirs::config_dir().context("Missing config directory")?;
-        p = Path::new(config_dir.as_path()).join(".aws/credentials");
-        if !p.exists() {
-            Err(anyhow!("No aws credentials configuration found"))?;
+        let config_p = Path::new(config_dir.as_path()).join(".aws/credentials");
+        if config_p.exists() {
+            return Ok(config_p);
+        }
+    }
+    Ok(p)
+}
+
+/// Credentials file with the long term keys of the default profile replaced,
+/// the other profiles and lines are kept as they are
+fn replace_default_profile_keys(
+    data: &str,
+    access_key_id: &str,
+    secret_access_key: &str,
+) -> String {
+    let kv_regex = Regex::new(r"^\s*([^\s=]+)\s*=").unwrap();
+    let mut in_default_profile = false;
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_default_profile = trimmed == "[default]";
+        } else if in_default_profile {
+            match kv_regex
+                .captures(line)
+                .and_then(|c| c.get(1))
+                .map(|k| k.as_str())
+            {
+                Some("aws_access_key_id") => {
+                    lines.push(format!("aws_access_key_id = {}", access_key_id));
+                    continue;
+                }
+                Some("aws_secret_access_key") => {
+                    lines.push(format!("aws_secret_access_key = {}", secret_access_key));
+                    continue;
+                }
+                _ => {}
+            }
         }
+        lines.push(line.to_owned());
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Replaces the long term keys of the default profile of the aws credentials file
+pub fn update_default_profile_keys(access_key_id: &str, secret_access_key: &str) -> Result<()> {
+    let p = aws_credentials_path()?;
+    let mut data = String::new();
+    File::open(&p)
+        .with_context(|| format!("could not read {:?}", p))?
+        .read_to_string(&mut data)?;
+
+    let updated = replace_default_profile_keys(&data, access_key_id, secret_access_key);
+    let mut f = File::create(&p).with_context(|| format!("could not write {:?}", p))?;
+    f.write_all(updated.as_bytes())?;
+    Ok(())
+}
+
+fn get_profile_expiration(
+    profile_cfg: &HashMap<String, Option<String>>,
+) -> Option<DateTime<FixedOffset>> {
+    ["aws_expiration", "x_security_token_expires"]
+        .iter()
+        .filter_map(|k| profile_cfg.get(*k).and_then(|v| v.clone()))
+        .find_map(|v| match DateTime::parse_from_rfc3339(&v) {
+            Ok(expiration) => Some(expiration),
+            Err(e) => {
+                warn!("Invalid expiration {} in aws credentials: {}", v, e);
+                None
+            }
+        })
+}
+
+fn set_default_aws_credentials(cfg: &mut Config) -> Result<()> {
+    let p = aws_credentials_path()?;
+    if !p.exists() {
+        Err(anyhow!("No aws credentials configuration found"))?;
     }
     let config_file = File::open(p.as_path())?;
     let reader = BufReader::new(config_file);
@@ -204,13 +346,24 @@ fn set_default_aws_credentials(cfg: &mut Config) -> Result<()> {
             aws_secret_key = aws_secret_key_opt.clone();
         }
     }
+    // keep a still valid mfa session over the sts profile, the profile token
+    // may be the expired one that made us fall back to the mfa flow
+    let use_sts_profile = cfg.aws_sts_profile.is_some() || !cfg.is_token_valid();
     for (profile_name, profile_cfg) in &profiles_cfg_map {
+        if !use_sts_profile {
+            break;
+        }
         debug!("Parsing profile {}", profile_name);
+        if profile_cfg.contains_key(EXPORTED_PROFILE_KEY) {
+            debug!("Skipping exported profile {}", profile_name);
+            continue;
+        }
         if let Some(aws_session_token_opt) = profile_cfg.get("aws_session_token") {
             if aws_session_token_opt.is_some() {
                 debug!("Found aws_session_token for profile {}", &profile_name);
                 cfg.aws_sts_profile = Some(profile_name.clone());
                 cfg.aws_session_token = aws_session_token_opt.clone();
+                cfg.aws_session_expiration = get_profile_expiration(profile_cfg);
             }
             if let Some(aws_access_key) =
                 profile_cfg.get("aws_access_key_id").and_then(|v| v.clone())
@@ -238,3 +391,48 @@ fn set_default_aws_credentials(cfg: &mut Config) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_the_keys_of_the_default_profile_only() {
+        let data = "\
+# long term keys
+[default]
+aws_access_key_id = AKIAOLD
+aws_secret_access_key=old-secret
+region = eu-west-1
+
+[mfa]
+aws_access_key_id = ASIATEMP
+aws_secret_access_key = temp-secret
+aws_session_token = token
+";
+        assert_eq!(
+            replace_default_profile_keys(data, "AKIANEW", "new-secret"),
+            "\
+# long term keys
+[default]
+aws_access_key_id = AKIANEW
+aws_secret_access_key = new-secret
+region = eu-west-1
+
+[mfa]
+aws_access_key_id = ASIATEMP
+aws_secret_access_key = temp-secret
+aws_session_token = token
+"
+        );
+    }
+
+    #[test]
+    fn keeps_the_files_without_default_profile() {
+        let data = "[other]\naws_access_key_id = AKIAOTHER\n";
+        assert_eq!(
+            replace_default_profile_keys(data, "AKIANEW", "new-secret"),
+            data
+        );
+    }
+}
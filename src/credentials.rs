@@ -1,31 +1,74 @@
 use crate::client::HttpClient;
 use crate::config;
+use crate::region;
 
 use anyhow::Context;
 use anyhow::Result;
-use dialoguer::Input;
-use rusoto_core::region::Region;
+use console::style;
+use dialoguer::{Input, Select};
 use rusoto_credential::StaticProvider;
-use rusoto_sts::{AssumeRoleRequest, GetSessionTokenRequest, Sts, StsClient};
+use rusoto_iam::{Iam, IamClient, ListMFADevicesRequest};
+use rusoto_sts::{
+    AssumeRoleRequest, GetCallerIdentityRequest, GetSessionTokenRequest, PolicyDescriptorType, Sts,
+    StsClient,
+};
 use std::sync::Arc;
 
 use chrono::prelude::*;
 
-#[derive(Debug)]
+const DEFAULT_MFA_MAX_ATTEMPTS: u32 = 3;
+
+/// Session policy of the inventory commands when none is configured for the role,
+/// the assumed sessions can only read ECS whatever the permissions of the role
+const INVENTORY_SESSION_POLICY: &str = r#"{
+    "Version": "2012-10-17",
+    "Statement": [
+        {
+            "Effect": "Allow",
+            "Action": ["ecs:Describe*", "ecs:List*", "events:ListRules", "events:ListTargetsByRule"],
+            "Resource": "*"
+        }
+    ]
+}"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionScope {
+    /// Read only inventory done by the tool itself
+    Inventory,
+    /// Credentials handed over to other tools
+    Full,
+}
+
+#[derive(Debug, Clone)]
 pub struct Credentials {
     pub aws_access_key: String,
     pub aws_secret_key: String,
     pub aws_sts_token: String,
+    pub aws_expiration: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug)]
+pub struct Identity {
+    pub account: String,
+    pub arn: String,
+    pub user_id: String,
 }
 
 pub async fn update_temp_credentials(
     config: &mut config::Config,
     client: Arc<HttpClient>,
 ) -> Result<()> {
-    if !config.is_token_valid() {
-        let mfa: String = Input::<String>::new()
-            .with_prompt("Please enter your mfa")
-            .interact()?;
+    let mut token_valid = config.is_token_valid();
+    if token_valid && config.needs_token_check() {
+        token_valid = is_session_accepted(config, client.clone()).await;
+    }
+    if !token_valid {
+        if config.aws_sts_profile.take().is_some() {
+            info!("Session token of .aws/credentials is expired, falling back to mfa");
+        }
+        if config.aws_mfa_device_arn.is_none() {
+            select_mfa_device(config, client.clone()).await?;
+        }
 
         let cred_provider = StaticProvider::new(
             config.aws_access_key_id.clone(),
@@ -34,26 +77,76 @@ pub async fn update_temp_credentials(
             None,
         );
 
-        let sts_client = StsClient::new_with(client, cred_provider, Region::EuWest1);
-
-        let get_session_token = GetSessionTokenRequest {
-            duration_seconds: None,
-            serial_number: Some(
-                config
-                    .aws_mfa_device_arn
-                    .clone()
-                    .ok_or(anyhow!("mfa device arn is not set, cannot get sts token"))?,
-            ),
-            token_code: Some(mfa),
-        };
-        let get_session_token_res = sts_client
-            .get_session_token(get_session_token)
-            .await
-            .context("Failed getting STS credentials")?;
+        let sts_client = StsClient::new_with(client, cred_provider, region::sts_region(config));
+
+        let serial_number = config
+            .aws_mfa_device_arn
+            .clone()
+            .ok_or(anyhow!("mfa device arn is not set, cannot get sts token"))?;
+        region::check_arn_partition(config, &serial_number)?;
+        let max_attempts = config
+            .mfa_max_attempts
+            .unwrap_or(DEFAULT_MFA_MAX_ATTEMPTS)
+            .max(1);
+        let mut attempt = 0;
+        let mut last_code: Option<(String, i64)> = None;
 
-        let credentials = get_session_token_res
-            .credentials
-            .context("Got not credentials")?;
+        let credentials = loop {
+            let mfa: String = Input::<String>::new()
+                .with_prompt("Please enter your mfa")
+                .interact()?;
+
+            // totp codes change every 30 seconds and sts refuses a code used twice
+            let code_window = (mfa.clone(), Utc::now().timestamp() / 30);
+            if last_code.as_ref() == Some(&code_window) {
+                eprintln!(
+                    "{}",
+                    style("This code was already entered, wait for the next one").yellow()
+                );
+                continue;
+            }
+            last_code = Some(code_window);
+            attempt += 1;
+
+            let get_session_token = GetSessionTokenRequest {
+                duration_seconds: None,
+                serial_number: Some(serial_number.clone()),
+                token_code: Some(mfa),
+            };
+            match sts_client.get_session_token(get_session_token).await {
+                Ok(get_session_token_res) => {
+                    break get_session_token_res
+                        .credentials
+                        .context("Got not credentials")?
+                }
+                Err(e) => {
+                    let msg = e.to_string();
+                    if msg.contains("unable to validate MFA code") {
+                        return Err(e).context(format!(
+                            "MFA code could not be validated, check the device {} and its clock",
+                            serial_number
+                        ));
+                    }
+                    if !msg.contains("invalid MFA one time pass code") {
+                        return Err(e).context("Failed getting STS credentials");
+                    }
+                    if attempt >= max_attempts {
+                        return Err(e).context(format!(
+                            "Invalid mfa code after {} attempts, check the clock of the device",
+                            attempt
+                        ));
+                    }
+                    eprintln!(
+                        "{}",
+                        style(format!(
+                            "Invalid mfa code, please retry ({}/{})",
+                            attempt, max_attempts
+                        ))
+                        .yellow()
+                    );
+                }
+            }
+        };
 
         config.aws_temp_access_key_id = Some(credentials.access_key_id);
         config.aws_temp_secret_access_key = Some(credentials.secret_access_key);
@@ -67,32 +160,122 @@ pub async fn update_temp_credentials(
     Ok(())
 }
 
-pub async fn assume_role(
-    config: &config::Config,
-    client: Arc<HttpClient>,
-    role_arn: &String,
-) -> Result<Credentials> {
+/// Lets the user pick the mfa device among the ones of the IAM user,
+/// falls back to typing its ARN when they cannot be listed
+async fn select_mfa_device(config: &mut config::Config, client: Arc<HttpClient>) -> Result<()> {
     let cred_provider = StaticProvider::new(
-        config
+        config.aws_access_key_id.clone(),
+        config.aws_secret_access_key.clone(),
+        None,
+        None,
+    );
+    let iam_client = IamClient::new_with(client, cred_provider, region::iam_region(config));
+
+    let serial_numbers: Vec<String> = match iam_client
+        .list_mfa_devices(ListMFADevicesRequest::default())
+        .await
+    {
+        Ok(list_mfa_devices_res) => list_mfa_devices_res
+            .mfa_devices
+            .into_iter()
+            .map(|d| d.serial_number)
+            .collect(),
+        Err(e) => {
+            warn!("Could not list mfa devices: {}", e);
+            Vec::new()
+        }
+    };
+
+    if serial_numbers.is_empty() {
+        return config.ask_mfa_device_arn();
+    }
+    let selected = Select::new()
+        .with_prompt("Select your MFA device")
+        .items(&serial_numbers)
+        .default(0)
+        .interact()?;
+    config.aws_mfa_device_arn = serial_numbers.into_iter().nth(selected);
+    Ok(())
+}
+
+pub fn session_credentials(config: &config::Config) -> Result<Credentials> {
+    Ok(Credentials {
+        aws_access_key: config
             .aws_temp_access_key_id
             .clone()
             .ok_or(anyhow!("aws_temp_access_key_id is not set"))?,
-        config
+        aws_secret_key: config
             .aws_temp_secret_access_key
             .clone()
             .ok_or(anyhow!("aws_temp_secret_access_keys not set"))?,
-        config.aws_session_token.clone(),
+        aws_sts_token: config
+            .aws_session_token
+            .clone()
+            .ok_or(anyhow!("aws_session_token is not set"))?,
+        aws_expiration: config.aws_session_expiration,
+    })
+}
+
+async fn is_session_accepted(config: &config::Config, client: Arc<HttpClient>) -> bool {
+    let res = match session_credentials(config) {
+        Ok(creds) => get_caller_identity(config, client, &creds).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(identity) => {
+            debug!("Session token is valid for {}", identity.arn);
+            true
+        }
+        Err(e) => {
+            warn!("Session token was rejected: {:#}", e);
+            false
+        }
+    }
+}
+
+pub async fn assume_role(
+    config: &config::Config,
+    client: Arc<HttpClient>,
+    role_arn: &String,
+    scope: SessionScope,
+) -> Result<Credentials> {
+    let session_creds = session_credentials(config)?;
+    let cred_provider = StaticProvider::new(
+        session_creds.aws_access_key,
+        session_creds.aws_secret_key,
+        Some(session_creds.aws_sts_token),
         None,
     );
-    let sts_client = StsClient::new_with(client, cred_provider, Region::EuWest1);
+    region::check_arn_partition(config, role_arn)?;
+    let sts_client = StsClient::new_with(client, cred_provider, region::sts_region(config));
 
     debug!("Assuming role {}", role_arn.to_string());
 
-    let assume_role_request = AssumeRoleRequest {
+    let mut assume_role_request = AssumeRoleRequest {
         role_arn: role_arn.to_owned(),
         role_session_name: "dummy".to_owned(),
         ..Default::default()
     };
+    match config.role_session_policies.get(role_arn) {
+        Some(session_policy) => {
+            // the inline policy may be given as a json document or as an already encoded string
+            assume_role_request.policy = session_policy.policy.as_ref().map(|p| match p {
+                serde_json::Value::String(encoded) => encoded.clone(),
+                document => document.to_string(),
+            });
+            assume_role_request.policy_arns = session_policy.policy_arns.as_ref().map(|arns| {
+                arns.iter()
+                    .map(|arn| PolicyDescriptorType {
+                        arn: Some(arn.clone()),
+                    })
+                    .collect()
+            });
+        }
+        None if scope == SessionScope::Inventory => {
+            assume_role_request.policy = Some(INVENTORY_SESSION_POLICY.to_owned());
+        }
+        None => {}
+    }
 
     let assume_role_res = sts_client
         .assume_role(assume_role_request)
@@ -107,5 +290,43 @@ pub async fn assume_role(
         aws_access_key: credentials.access_key_id,
         aws_secret_key: credentials.secret_access_key,
         aws_sts_token: credentials.session_token,
+        aws_expiration: DateTime::parse_from_rfc3339(&credentials.expiration).ok(),
+    })
+}
+
+/// Credentials of the given role or of the MFA session when no role is given
+pub async fn get_credentials(
+    config: &config::Config,
+    client: Arc<HttpClient>,
+    role_arn: &Option<String>,
+) -> Result<Credentials> {
+    match role_arn {
+        Some(role_arn) => assume_role(config, client, role_arn, SessionScope::Full).await,
+        None => session_credentials(config),
+    }
+}
+
+pub async fn get_caller_identity(
+    config: &config::Config,
+    client: Arc<HttpClient>,
+    creds: &Credentials,
+) -> Result<Identity> {
+    let cred_provider = StaticProvider::new(
+        creds.aws_access_key.clone(),
+        creds.aws_secret_key.clone(),
+        Some(creds.aws_sts_token.clone()),
+        None,
+    );
+    let sts_client = StsClient::new_with(client, cred_provider, region::sts_region(config));
+
+    let caller_identity_res = sts_client
+        .get_caller_identity(GetCallerIdentityRequest {})
+        .await
+        .context("Failed getting caller identity")?;
+
+    Ok(Identity {
+        account: caller_identity_res.account.unwrap_or_default(),
+        arn: caller_identity_res.arn.unwrap_or_default(),
+        user_id: caller_identity_res.user_id.unwrap_or_default(),
     })
 }
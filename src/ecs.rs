@@ -2,84 +2,333 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::vec::Vec;
 
+use crate::cache::{TaskDefinitionCache, TaskDefinitionRevision};
 use crate::client::HttpClient;
 use crate::credentials::Credentials;
+use crate::errors::{ErrorKind, DEADLINE_REACHED};
+use anyhow::Context;
 use anyhow::Result;
+use chrono::prelude::*;
+use console::style;
+use std::future::Future;
+use tokio::time::Instant;
 
 use futures::future::join_all;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use rusoto_core::region::Region;
-use rusoto_credential::StaticProvider;
+use rusoto_core::request::DispatchSignedRequest;
+use rusoto_core::signature::SignedRequest;
+use rusoto_credential::{AwsCredentials, StaticProvider};
 
 use rusoto_ecs::{
-    DescribeServicesRequest, DescribeTaskDefinitionRequest, Ecs, EcsClient, ListClustersRequest,
-    ListServicesRequest,
+    DescribeClustersRequest, DescribeServicesRequest, DescribeTasksRequest, Ecs, EcsClient,
+    ListClustersRequest, ListServicesRequest, ListTaskDefinitionsRequest, ListTasksRequest,
 };
+use rusoto_events::{EventBridge, EventBridgeClient, ListRulesRequest, ListTargetsByRuleRequest};
+
+/// Largest pages and batches accepted by the ECS api
+const LIST_MAX_RESULTS: i64 = 100;
+const DESCRIBE_SERVICES_MAX: usize = 10;
+const DESCRIBE_CLUSTERS_MAX: usize = 100;
+const DESCRIBE_TASKS_MAX: usize = 100;
+
+/// Group of the tasks started by a service, the other ones are standalone
+const SERVICE_TASK_GROUP_PREFIX: &str = "service:";
+
+/// Starter of the tasks of the EventBridge rules, already reported with their rule
+const EVENTS_RULE_STARTED_BY_PREFIX: &str = "events-rule/";
+
+/// Prefix of the targets of the ECS json api, for the requests rusoto_ecs can't describe
+const ECS_TARGET_PREFIX: &str = "AmazonEC2ContainerServiceV20141113";
+
+/// Key of the failures of the scheduled rules scan, next to the cluster ones
+pub const SCHEDULED_RULES_FAILURE_KEY: &str = "scheduled-rules";
+
+/// Maximum numbers of ECS requests in flight, in total and for each account
+pub struct ConcurrencyLimits {
+    global: Arc<Semaphore>,
+    per_account: usize,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(global: usize, per_account: usize) -> ConcurrencyLimits {
+        ConcurrencyLimits {
+            global: Arc::new(Semaphore::new(global.max(1))),
+            per_account: per_account.max(1),
+        }
+    }
+}
+
+/// ECS client of an account, each request waits for a permit of the account and a global one,
+/// the EventBridge client lists the rules starting ECS tasks and the dispatcher sends the
+/// requests whose response fields are missing from rusoto_ecs
+pub struct LimitedEcsClient {
+    client: EcsClient,
+    events_client: EventBridgeClient,
+    dispatcher: Arc<HttpClient>,
+    credentials: AwsCredentials,
+    region: Region,
+    account_permits: Semaphore,
+    global_permits: Arc<Semaphore>,
+    task_definitions: Arc<TaskDefinitionCache>,
+}
+
+impl LimitedEcsClient {
+    async fn permits(&self) -> (SemaphorePermit<'_>, SemaphorePermit<'_>) {
+        let account_permit = self.account_permits.acquire().await;
+        let global_permit = self.global_permits.acquire().await;
+        (account_permit, global_permit)
+    }
+}
+
+/// Runs the future until the optional deadline of the scan
+pub async fn until_deadline<T>(
+    deadline: Option<Instant>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!(DEADLINE_REACHED)),
+        },
+        None => fut.await,
+    }
+}
+
+#[derive(Debug)]
+pub struct Deployment {
+    pub status: String,
+    pub task_definition: String,
+    pub desired_count: i64,
+    pub running_count: i64,
+    pub pending_count: i64,
+    /// IN_PROGRESS, COMPLETED or FAILED, only set for the rolling updates of ECS
+    pub rollout_state: Option<String>,
+    pub rollout_state_reason: Option<String>,
+}
+
+/// Counts and deployments of a service, to tell whether the deployed image is running
+#[derive(Debug)]
+pub struct ServiceStatus {
+    pub desired_count: i64,
+    pub running_count: i64,
+    pub pending_count: i64,
+    pub deployments: Vec<Deployment>,
+}
+
+fn json_string(value: &serde_json::Value) -> Option<String> {
+    value.as_str().map(|s| s.to_owned())
+}
+
+impl ServiceStatus {
+    /// Status of a service of the DescribeServices response, rusoto_ecs 0.44 predates
+    /// the rollout state of the deployments
+    fn of_service(service: &serde_json::Value) -> ServiceStatus {
+        ServiceStatus {
+            desired_count: service["desiredCount"].as_i64().unwrap_or(0),
+            running_count: service["runningCount"].as_i64().unwrap_or(0),
+            pending_count: service["pendingCount"].as_i64().unwrap_or(0),
+            deployments: service["deployments"]
+                .as_array()
+                .map(|deployments| {
+                    deployments
+                        .iter()
+                        .map(|d| Deployment {
+                            status: json_string(&d["status"]).unwrap_or_default(),
+                            task_definition: json_string(&d["taskDefinition"]).unwrap_or_default(),
+                            desired_count: d["desiredCount"].as_i64().unwrap_or(0),
+                            running_count: d["runningCount"].as_i64().unwrap_or(0),
+                            pending_count: d["pendingCount"].as_i64().unwrap_or(0),
+                            rollout_state: json_string(&d["rolloutState"]),
+                            rollout_state_reason: json_string(&d["rolloutStateReason"]),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// A deployment fails when its circuit breaker fires
+    pub fn has_failed_deployment(&self) -> bool {
+        self.deployments
+            .iter()
+            .any(|d| d.rollout_state.as_deref() == Some("FAILED"))
+    }
+
+    pub fn is_rolling_out(&self) -> bool {
+        self.deployments.len() > 1
+            || self
+                .deployments
+                .iter()
+                .any(|d| d.rollout_state.as_deref() == Some("IN_PROGRESS"))
+    }
+
+    pub fn is_below_desired_count(&self) -> bool {
+        self.running_count < self.desired_count
+    }
+}
+
+/// What runs the image, the services and the tasks started outside of them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Workload {
+    Service,
+    /// Running task started with RunTask, by its group
+    StandaloneTask(String),
+    /// EventBridge rule starting the task on a schedule or on events
+    ScheduledRule(String),
+}
 
 #[derive(Debug)]
 pub struct Image {
     pub image_name: String,
     pub task_definition_name: String,
     pub service_name: String,
+    pub service_status: Option<ServiceStatus>,
+    pub workload: Workload,
+}
+
+/// Sends a request of the ECS json api and gives back its response as it is, for the
+/// fields missing from rusoto_ecs 0.44
+async fn call_ecs_json(
+    ecs_client: &LimitedEcsClient,
+    operation: &str,
+    payload: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let mut request = SignedRequest::new("POST", "ecs", &ecs_client.region, "/");
+    request.set_content_type("application/x-amz-json-1.1".to_owned());
+    request.add_header(
+        "x-amz-target",
+        &format!("{}.{}", ECS_TARGET_PREFIX, operation),
+    );
+    request.set_payload(Some(serde_json::to_vec(&payload)?));
+    request.sign(&ecs_client.credentials);
+    let response = {
+        let _permits = ecs_client.permits().await;
+        ecs_client
+            .dispatcher
+            .dispatch(request, None)
+            .await?
+            .buffer()
+            .await?
+    };
+    if !response.status.is_success() {
+        return Err(anyhow!(
+            "{} failed with {}: {}",
+            operation,
+            response.status,
+            String::from_utf8_lossy(&response.body)
+        ));
+    }
+    Ok(serde_json::from_slice(&response.body)
+        .with_context(|| format!("Invalid {} response", operation))?)
+}
+
+/// Container images and registration date of a revision, rusoto_ecs 0.44 predates the
+/// registeredAt field
+async fn describe_task_definition(
+    ecs_client: &LimitedEcsClient,
+    task_definition: String,
+) -> Result<TaskDefinitionRevision> {
+    let body = call_ecs_json(
+        ecs_client,
+        "DescribeTaskDefinition",
+        serde_json::json!({ "taskDefinition": task_definition }),
+    )
+    .await
+    .with_context(|| format!("Could not describe {}", task_definition))?;
+    let task_definition = &body["taskDefinition"];
+    let container_images = task_definition["containerDefinitions"]
+        .as_array()
+        .map(|container_definitions| {
+            container_definitions
+                .iter()
+                .filter_map(|cd| {
+                    let image = cd["image"].as_str()?;
+                    Some((
+                        cd["name"].as_str().unwrap_or("").to_owned(),
+                        image.to_owned(),
+                    ))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    // the json api gives the dates in seconds since the epoch
+    let registered_at = task_definition["registeredAt"]
+        .as_f64()
+        .map(|secs| Utc.timestamp_millis((secs * 1000.0) as i64));
+    Ok(TaskDefinitionRevision {
+        container_images,
+        registered_at,
+    })
+}
+
+/// Container images and registration date of the task definition, described once per revision
+pub async fn get_task_definition_revision(
+    ecs_client: &LimitedEcsClient,
+    task_definition: &str,
+) -> Result<TaskDefinitionRevision> {
+    ecs_client
+        .task_definitions
+        .get_or_fetch(
+            task_definition,
+            describe_task_definition(ecs_client, task_definition.to_owned()),
+        )
+        .await
 }
 
+/// Image of the last container of the task definition
 pub async fn get_image_of_task_definition(
-    ecs_client: &EcsClient,
+    ecs_client: &LimitedEcsClient,
     task_definition: String,
     service_name: String,
 ) -> Result<Option<Image>> {
-    let task_definition_req = DescribeTaskDefinitionRequest {
-        task_definition,
-        include: None,
-    };
-    let task_definition_res = ecs_client
-        .describe_task_definition(task_definition_req)
-        .await?;
-
-    Ok(task_definition_res.task_definition.and_then(move |td| {
-        td.clone()
-            .task_definition_arn
-            .and_then(move |td_arn| {
-                td.container_definitions
-                    .and_then(|cds| cds.last().map(|cd| (td_arn, cd.clone())))
-            })
-            .and_then(move |(td_arn, cd)| {
-                cd.image.map(|i| Image {
-                    image_name: i,
-                    task_definition_name: td_arn,
-                    service_name,
-                })
-            })
-    }))
+    let revision = get_task_definition_revision(ecs_client, &task_definition).await?;
+
+    Ok(revision
+        .container_images
+        .into_iter()
+        .last()
+        .map(|(_, i)| Image {
+            image_name: i,
+            task_definition_name: task_definition,
+            service_name,
+            service_status: None,
+            workload: Workload::Service,
+        }))
 }
 
 async fn get_images_of_services(
-    ecs_client: &EcsClient,
+    ecs_client: &LimitedEcsClient,
     service_arns: Vec<String>,
     cluster_name: String,
 ) -> Result<Vec<Image>> {
     let mut images: Vec<Image> = Vec::new();
-    let describe_services_req = DescribeServicesRequest {
-        cluster: Some(cluster_name.clone()),
-        services: service_arns,
-        include: None,
-    };
+    let describe_services_res = call_ecs_json(
+        ecs_client,
+        "DescribeServices",
+        serde_json::json!({ "cluster": cluster_name, "services": service_arns }),
+    )
+    .await?;
 
-    let describe_services_res = ecs_client.describe_services(describe_services_req).await?;
-
-    if let Some(services) = describe_services_res.services {
-        let task_definitions: Vec<(String, String)> = services
-            .into_iter()
+    if let Some(services) = describe_services_res["services"].as_array() {
+        let task_definitions: Vec<(String, String, ServiceStatus)> = services
+            .iter()
             .filter_map(|service| {
-                service
-                    .clone()
-                    .task_definition
-                    .and_then(|td| service.service_name.map(|sn| (td, sn)))
+                let td = json_string(&service["taskDefinition"])?;
+                let sn = json_string(&service["serviceName"])?;
+                Some((td, sn, ServiceStatus::of_service(service)))
             })
             .collect();
         let get_images_futures = task_definitions
             .into_iter()
-            .map(|(td, sn)| get_image_of_task_definition(ecs_client, td, sn));
+            .map(|(td, sn, status)| async move {
+                let image = get_image_of_task_definition(ecs_client, td, sn).await?;
+                Ok::<_, anyhow::Error>(image.map(|image| Image {
+                    service_status: Some(status),
+                    ..image
+                }))
+            });
 
         let get_images_results = join_all(get_images_futures).await;
 
@@ -96,29 +345,28 @@ async fn get_images_of_services(
 }
 
 pub async fn get_images_of_a_cluster(
-    ecs_client: &EcsClient,
+    ecs_client: &LimitedEcsClient,
     cluster_name: String,
 ) -> Result<(String, Vec<Image>)> {
     let mut next_token: Option<String> = None;
 
-    let mut all_images: Vec<Image> = Vec::new();
+    let mut all_service_arns: Vec<String> = Vec::new();
 
     loop {
         let list_services_req = ListServicesRequest {
-            max_results: None,
+            max_results: Some(LIST_MAX_RESULTS),
             next_token,
             cluster: Some(cluster_name.clone()),
             launch_type: None,
             scheduling_strategy: None,
         };
 
-        let list_services_res = ecs_client.list_services(list_services_req).await?;
+        let list_services_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client.client.list_services(list_services_req).await?
+        };
         if let Some(service_arns) = list_services_res.service_arns {
-            if !service_arns.is_empty() {
-                let got_images =
-                    get_images_of_services(ecs_client, service_arns, cluster_name.clone()).await?;
-                all_images.extend(got_images);
-            }
+            all_service_arns.extend(service_arns);
         }
         if list_services_res.next_token.is_none() {
             break;
@@ -126,19 +374,222 @@ pub async fn get_images_of_a_cluster(
         next_token = list_services_res.next_token;
     }
 
+    // the services are described in concurrent batches once all the pages are listed
+    let get_images_futures = all_service_arns
+        .chunks(DESCRIBE_SERVICES_MAX)
+        .map(|service_arns| {
+            get_images_of_services(ecs_client, service_arns.to_vec(), cluster_name.clone())
+        });
+    let get_images_results: Result<Vec<Vec<Image>>> =
+        join_all(get_images_futures).await.into_iter().collect();
+    let mut all_images: Vec<Image> = get_images_results?.into_iter().flatten().collect();
+    all_images.extend(get_images_of_standalone_tasks(ecs_client, &cluster_name).await?);
+
     Ok((cluster_name.clone(), all_images))
 }
 
-pub async fn get_clusters(ecs_client: &EcsClient) -> Result<Vec<String>> {
+/// Images of the running tasks that do not belong to a service, once per group and
+/// task definition
+async fn get_images_of_standalone_tasks(
+    ecs_client: &LimitedEcsClient,
+    cluster_name: &str,
+) -> Result<Vec<Image>> {
+    let mut task_arns: Vec<String> = Vec::new();
+    let mut list_tasks_req = ListTasksRequest {
+        cluster: Some(cluster_name.to_owned()),
+        desired_status: Some("RUNNING".to_owned()),
+        max_results: Some(LIST_MAX_RESULTS),
+        ..Default::default()
+    };
+    loop {
+        let list_tasks_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client.client.list_tasks(list_tasks_req.clone()).await?
+        };
+        task_arns.extend(list_tasks_res.task_arns.unwrap_or_default());
+        if list_tasks_res.next_token.is_none() {
+            break;
+        }
+        list_tasks_req.next_token = list_tasks_res.next_token;
+    }
+
+    let mut standalone_tasks: Vec<(String, String)> = Vec::new();
+    for batch in task_arns.chunks(DESCRIBE_TASKS_MAX) {
+        let describe_tasks_req = DescribeTasksRequest {
+            cluster: Some(cluster_name.to_owned()),
+            tasks: batch.to_vec(),
+            include: None,
+        };
+        let describe_tasks_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client.client.describe_tasks(describe_tasks_req).await?
+        };
+        for task in describe_tasks_res.tasks.unwrap_or_default() {
+            let group = task.group.unwrap_or_default();
+            let started_by_rule = task
+                .started_by
+                .as_deref()
+                .map_or(false, |by| by.starts_with(EVENTS_RULE_STARTED_BY_PREFIX));
+            if group.starts_with(SERVICE_TASK_GROUP_PREFIX) || started_by_rule {
+                continue;
+            }
+            if let Some(td) = task.task_definition_arn {
+                if !standalone_tasks.contains(&(td.clone(), group.clone())) {
+                    standalone_tasks.push((td, group));
+                }
+            }
+        }
+    }
+
+    let get_images_futures = standalone_tasks.into_iter().map(|(td, group)| async move {
+        let image = get_image_of_task_definition(ecs_client, td, group.clone()).await?;
+        Ok::<_, anyhow::Error>(image.map(|image| Image {
+            workload: Workload::StandaloneTask(group),
+            ..image
+        }))
+    });
+    let images: Result<Vec<Option<Image>>> =
+        join_all(get_images_futures).await.into_iter().collect();
+    Ok(images?.into_iter().flatten().collect())
+}
+
+/// Images of the tasks started by the EventBridge rules of the account, by cluster arn
+async fn get_images_of_scheduled_rules(
+    ecs_client: &LimitedEcsClient,
+) -> Result<HashMap<String, Vec<Image>>> {
+    let mut rule_names: Vec<String> = Vec::new();
+    let mut list_rules_req = ListRulesRequest {
+        limit: Some(LIST_MAX_RESULTS),
+        ..Default::default()
+    };
+    loop {
+        let list_rules_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client
+                .events_client
+                .list_rules(list_rules_req.clone())
+                .await?
+        };
+        rule_names.extend(
+            list_rules_res
+                .rules
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|rule| rule.name),
+        );
+        if list_rules_res.next_token.is_none() {
+            break;
+        }
+        list_rules_req.next_token = list_rules_res.next_token;
+    }
+
+    // (cluster arn, task definition, rule name) of the ECS targets
+    let mut ecs_targets: Vec<(String, String, String)> = Vec::new();
+    for rule_name in rule_names {
+        let mut list_targets_req = ListTargetsByRuleRequest {
+            rule: rule_name.clone(),
+            limit: Some(LIST_MAX_RESULTS),
+            ..Default::default()
+        };
+        loop {
+            let list_targets_res = {
+                let _permits = ecs_client.permits().await;
+                ecs_client
+                    .events_client
+                    .list_targets_by_rule(list_targets_req.clone())
+                    .await?
+            };
+            for target in list_targets_res.targets.unwrap_or_default() {
+                if let Some(ecs_parameters) = target.ecs_parameters {
+                    ecs_targets.push((
+                        target.arn,
+                        ecs_parameters.task_definition_arn,
+                        rule_name.clone(),
+                    ));
+                }
+            }
+            if list_targets_res.next_token.is_none() {
+                break;
+            }
+            list_targets_req.next_token = list_targets_res.next_token;
+        }
+    }
+
+    let get_images_futures =
+        ecs_targets
+            .into_iter()
+            .map(|(cluster_arn, td, rule_name)| async move {
+                let image = get_image_of_task_definition(ecs_client, td, rule_name.clone()).await?;
+                Ok::<_, anyhow::Error>(image.map(|image| {
+                    (
+                        cluster_arn,
+                        Image {
+                            workload: Workload::ScheduledRule(rule_name),
+                            ..image
+                        },
+                    )
+                }))
+            });
+    let images: Result<Vec<Option<(String, Image)>>> =
+        join_all(get_images_futures).await.into_iter().collect();
+
+    let mut images_by_cluster: HashMap<String, Vec<Image>> = HashMap::new();
+    for (cluster_arn, image) in images?.into_iter().flatten() {
+        images_by_cluster
+            .entry(cluster_arn)
+            .or_insert_with(Vec::new)
+            .push(image);
+    }
+    Ok(images_by_cluster)
+}
+
+/// Numbers of active services and running tasks of the clusters, described by batches
+async fn get_clusters_activity(
+    ecs_client: &LimitedEcsClient,
+    cluster_arns: &[String],
+) -> Result<HashMap<String, (i64, i64)>> {
+    let mut counts: HashMap<String, (i64, i64)> = HashMap::new();
+    for batch in cluster_arns.chunks(DESCRIBE_CLUSTERS_MAX) {
+        let describe_clusters_req = DescribeClustersRequest {
+            clusters: Some(batch.to_vec()),
+            include: None,
+        };
+        let describe_clusters_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client
+                .client
+                .describe_clusters(describe_clusters_req)
+                .await?
+        };
+        for cluster in describe_clusters_res.clusters.unwrap_or_default() {
+            if let (Some(arn), Some(services_count), Some(tasks_count)) = (
+                cluster.cluster_arn,
+                cluster.active_services_count,
+                cluster.running_tasks_count,
+            ) {
+                counts.insert(arn, (services_count, tasks_count));
+            }
+        }
+    }
+    Ok(counts)
+}
+
+pub async fn get_clusters(ecs_client: &LimitedEcsClient) -> Result<Vec<String>> {
     let mut clusters: Vec<String> = Vec::new();
 
     let mut list_clusters_req = ListClustersRequest {
-        max_results: None,
+        max_results: Some(LIST_MAX_RESULTS),
         next_token: None,
     };
 
     loop {
-        let list_clusters_res = ecs_client.list_clusters(list_clusters_req.clone()).await?;
+        let list_clusters_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client
+                .client
+                .list_clusters(list_clusters_req.clone())
+                .await?
+        };
         if let Some(cluster_arns) = list_clusters_res.cluster_arns {
             clusters.extend(cluster_arns);
         }
@@ -151,14 +602,20 @@ pub async fn get_clusters(ecs_client: &EcsClient) -> Result<Vec<String>> {
     Ok(clusters)
 }
 
+pub struct ClustersImages {
+    pub images: HashMap<String, Vec<Image>>,
+    pub failures: HashMap<String, anyhow::Error>,
+}
+
 pub async fn get_images_of_clusters(
-    ecs_client: &EcsClient,
+    ecs_client: &LimitedEcsClient,
     cluster_includes: &Vec<String>,
-) -> Result<HashMap<String, Vec<Image>>> {
-    let clusters = get_clusters(ecs_client).await?;
+    deadline: Option<Instant>,
+) -> Result<ClustersImages> {
+    let clusters = until_deadline(deadline, get_clusters(ecs_client)).await?;
     debug!("Got clusters {:?}", clusters);
 
-    let get_clusters_images_futures = clusters
+    let included_clusters: Vec<String> = clusters
         .into_iter()
         .filter(|cluster_arn| {
             cluster_includes.is_empty()
@@ -167,26 +624,272 @@ pub async fn get_images_of_clusters(
                     .find(|ci| cluster_arn.contains(*ci))
                     .is_some()
         })
-        .map(|cluster_arn| get_images_of_a_cluster(ecs_client, cluster_arn));
+        .collect();
+
+    let mut res = ClustersImages {
+        images: HashMap::new(),
+        failures: HashMap::new(),
+    };
+
+    // the clusters without services nor running tasks are not listed
+    let clusters_activity = until_deadline(
+        deadline,
+        get_clusters_activity(ecs_client, &included_clusters),
+    )
+    .await?;
+    let (empty_clusters, included_clusters): (Vec<String>, Vec<String>) = included_clusters
+        .into_iter()
+        .partition(|cluster_arn| clusters_activity.get(cluster_arn) == Some(&(0, 0)));
+    for cluster_arn in empty_clusters {
+        res.images.insert(cluster_arn, Vec::new());
+    }
+
+    let get_clusters_images_futures = included_clusters.iter().map(|cluster_arn| {
+        until_deadline(
+            deadline,
+            get_images_of_a_cluster(ecs_client, cluster_arn.clone()),
+        )
+    });
 
     let get_clusters_images_res = join_all(get_clusters_images_futures).await;
 
-    let mut res = HashMap::new();
-    for cluster_images_res in get_clusters_images_res {
-        let cluster_images_tuple = cluster_images_res?;
-        let (cluster_name, images) = cluster_images_tuple;
-        res.insert(cluster_name, images);
+    for (cluster_arn, cluster_images_res) in
+        included_clusters.into_iter().zip(get_clusters_images_res)
+    {
+        match cluster_images_res {
+            Ok((cluster_name, images)) => {
+                res.images.insert(cluster_name, images);
+            }
+            Err(e) => {
+                res.failures.insert(cluster_arn, e);
+            }
+        }
+    }
+
+    // the scheduled tasks are reported with the clusters they would run on
+    match until_deadline(deadline, get_images_of_scheduled_rules(ecs_client)).await {
+        Ok(images_by_cluster) => {
+            for (cluster_arn, images) in images_by_cluster {
+                if let Some(cluster_images) = res.images.get_mut(&cluster_arn) {
+                    cluster_images.extend(images);
+                }
+            }
+        }
+        // roles without EventBridge read access still scan their clusters
+        Err(e) if ErrorKind::of(&e) == ErrorKind::AccessDenied => {
+            eprintln!(
+                "{}",
+                style(format!("Not listing the scheduled tasks: {:#}", e)).yellow()
+            );
+        }
+        Err(e) => {
+            res.failures
+                .insert(SCHEDULED_RULES_FAILURE_KEY.to_owned(), e);
+        }
     }
 
     Ok(res)
 }
 
-pub fn build_ecs_client(client: Arc<HttpClient>, creds: Credentials) -> EcsClient {
+/// Family of a task definition arn, or of a family:revision
+pub fn get_task_definition_family(task_definition: &str) -> &str {
+    let family_revision = task_definition
+        .rsplit('/')
+        .next()
+        .unwrap_or(task_definition);
+    family_revision.split(':').next().unwrap_or(family_revision)
+}
+
+/// Cluster and deployed task definition of the service, looked up in the given
+/// clusters or in all of them
+pub async fn find_service_task_definition(
+    ecs_client: &LimitedEcsClient,
+    service_name: &str,
+    cluster: Option<&String>,
+) -> Result<(String, String)> {
+    let clusters = match cluster {
+        Some(cluster) => vec![cluster.clone()],
+        None => get_clusters(ecs_client).await?,
+    };
+
+    let describe_services_futures = clusters.iter().map(|cluster| async move {
+        let describe_services_req = DescribeServicesRequest {
+            cluster: Some(cluster.clone()),
+            services: vec![service_name.to_owned()],
+            include: None,
+        };
+        let describe_services_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client
+                .client
+                .describe_services(describe_services_req)
+                .await?
+        };
+        let task_definition = describe_services_res
+            .services
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|service| service.status.as_deref() != Some("INACTIVE"))
+            .find_map(|service| service.task_definition);
+        Ok::<_, anyhow::Error>(task_definition.map(|td| (cluster.clone(), td)))
+    });
+    let found: Result<Vec<Option<(String, String)>>> = join_all(describe_services_futures)
+        .await
+        .into_iter()
+        .collect();
+    let mut found: Vec<(String, String)> = found?.into_iter().flatten().collect();
+
+    match found.len() {
+        0 => Err(anyhow!("Service {} not found", service_name)),
+        1 => Ok(found.remove(0)),
+        _ => Err(anyhow!(
+            "Service {} found in several clusters ({}), pick one with --cluster",
+            service_name,
+            found
+                .iter()
+                .map(|(cluster, _)| cluster.rsplit('/').next().unwrap_or(cluster))
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Arns of the latest active revisions of the family, newest first
+pub async fn get_task_definition_history(
+    ecs_client: &LimitedEcsClient,
+    family: &str,
+    limit: usize,
+) -> Result<Vec<String>> {
+    let mut revisions: Vec<String> = Vec::new();
+    let mut list_task_definitions_req = ListTaskDefinitionsRequest {
+        family_prefix: Some(family.to_owned()),
+        sort: Some("DESC".to_owned()),
+        max_results: Some(LIST_MAX_RESULTS),
+        ..Default::default()
+    };
+    loop {
+        let list_task_definitions_res = {
+            let _permits = ecs_client.permits().await;
+            ecs_client
+                .client
+                .list_task_definitions(list_task_definitions_req.clone())
+                .await?
+        };
+        // the prefix also matches the families starting with the same name
+        revisions.extend(
+            list_task_definitions_res
+                .task_definition_arns
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|arn| get_task_definition_family(arn) == family),
+        );
+        if revisions.len() >= limit || list_task_definitions_res.next_token.is_none() {
+            break;
+        }
+        list_task_definitions_req.next_token = list_task_definitions_res.next_token;
+    }
+    revisions.truncate(limit);
+    Ok(revisions)
+}
+
+pub fn build_ecs_client(
+    client: Arc<HttpClient>,
+    creds: Credentials,
+    region: Region,
+    events_region: Region,
+    limits: &ConcurrencyLimits,
+    task_definitions: Arc<TaskDefinitionCache>,
+) -> LimitedEcsClient {
+    let credentials = AwsCredentials::new(
+        creds.aws_access_key.clone(),
+        creds.aws_secret_key.clone(),
+        Some(creds.aws_sts_token.clone()),
+        None,
+    );
     let cred_provider = StaticProvider::new(
         creds.aws_access_key,
         creds.aws_secret_key,
         Some(creds.aws_sts_token),
         None,
     );
-    EcsClient::new_with(client, cred_provider, Region::EuWest1)
+    LimitedEcsClient {
+        client: EcsClient::new_with(client.clone(), cred_provider.clone(), region.clone()),
+        events_client: EventBridgeClient::new_with(client.clone(), cred_provider, events_region),
+        dispatcher: client,
+        credentials,
+        region,
+        account_permits: Semaphore::new(limits.per_account),
+        global_permits: limits.global.clone(),
+        task_definitions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_rollout_state_of_the_deployments() {
+        let service = serde_json::json!({
+            "serviceName": "api",
+            "desiredCount": 2,
+            "runningCount": 2,
+            "pendingCount": 0,
+            "deployments": [
+                {
+                    "status": "PRIMARY",
+                    "taskDefinition": "arn:aws:ecs:eu-west-1:123456789012:task-definition/api:8",
+                    "desiredCount": 2,
+                    "runningCount": 0,
+                    "pendingCount": 0,
+                    "rolloutState": "FAILED",
+                    "rolloutStateReason": "ECS deployment circuit breaker: tasks failed to start."
+                },
+                {
+                    "status": "ACTIVE",
+                    "taskDefinition": "arn:aws:ecs:eu-west-1:123456789012:task-definition/api:7",
+                    "desiredCount": 2,
+                    "runningCount": 2,
+                    "pendingCount": 0,
+                    "rolloutState": "COMPLETED"
+                }
+            ]
+        });
+        let status = ServiceStatus::of_service(&service);
+        assert_eq!(status.desired_count, 2);
+        assert_eq!(status.deployments.len(), 2);
+        assert_eq!(
+            status.deployments[0].rollout_state.as_deref(),
+            Some("FAILED")
+        );
+        assert_eq!(
+            status.deployments[0].rollout_state_reason.as_deref(),
+            Some("ECS deployment circuit breaker: tasks failed to start.")
+        );
+        assert_eq!(status.deployments[1].rollout_state_reason, None);
+        assert!(status.has_failed_deployment());
+        assert!(status.is_rolling_out());
+    }
+
+    #[test]
+    fn a_completed_deployment_is_not_rolling_out() {
+        let service = serde_json::json!({
+            "desiredCount": 1,
+            "runningCount": 1,
+            "deployments": [
+                { "status": "PRIMARY", "desiredCount": 1, "runningCount": 1, "rolloutState": "COMPLETED" }
+            ]
+        });
+        let status = ServiceStatus::of_service(&service);
+        assert!(!status.has_failed_deployment());
+        assert!(!status.is_rolling_out());
+        assert!(!status.is_below_desired_count());
+
+        // the other deployment controllers have no rollout state
+        let status = ServiceStatus::of_service(&serde_json::json!({
+            "deployments": [{ "status": "PRIMARY" }]
+        }));
+        assert_eq!(status.deployments[0].rollout_state, None);
+        assert!(!status.is_rolling_out());
+    }
 }
@@ -0,0 +1,94 @@
+use anyhow::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ErrorKind {
+    AccessDenied,
+    ExpiredToken,
+    Throttling,
+    Tls,
+    Network,
+    Deadline,
+    Other,
+}
+
+pub const DEADLINE_REACHED: &str = "scan deadline reached";
+
+/// Error codes and messages identifying each kind, checked in order
+const KIND_PATTERNS: &[(ErrorKind, &[&str])] = &[
+    (ErrorKind::Deadline, &[DEADLINE_REACHED]),
+    (
+        ErrorKind::ExpiredToken,
+        &[
+            "ExpiredToken",
+            "RequestExpired",
+            "token included in the request is expired",
+        ],
+    ),
+    (
+        ErrorKind::AccessDenied,
+        &[
+            "AccessDenied",
+            "UnauthorizedOperation",
+            "not authorized to perform",
+        ],
+    ),
+    (
+        ErrorKind::Throttling,
+        &[
+            "Throttling",
+            "TooManyRequests",
+            "RequestLimitExceeded",
+            "Rate exceeded",
+        ],
+    ),
+    (
+        ErrorKind::Tls,
+        &[
+            "certificate verify failed",
+            "CERTIFICATE_VERIFY_FAILED",
+            "self signed certificate",
+            "unable to get local issuer certificate",
+            "UnknownIssuer",
+        ],
+    ),
+    (
+        ErrorKind::Network,
+        &[
+            "HttpDispatch",
+            "connection closed",
+            "error trying to connect",
+            "timed out",
+        ],
+    ),
+];
+
+impl ErrorKind {
+    /// Classifies an error from the AWS error codes or the dispatch failures found in its causes,
+    /// rusoto reports most of them as unknown errors holding the raw response body
+    pub fn of(err: &Error) -> ErrorKind {
+        let causes: Vec<String> = err
+            .chain()
+            .map(|cause| format!("{} {:?}", cause, cause))
+            .collect();
+        ErrorKind::of_message(&causes.join("\n"))
+    }
+
+    pub fn of_message(message: &str) -> ErrorKind {
+        KIND_PATTERNS
+            .iter()
+            .find(|(_, patterns)| patterns.iter().any(|p| message.contains(p)))
+            .map_or(ErrorKind::Other, |(kind, _)| *kind)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::AccessDenied => "access denied",
+            ErrorKind::ExpiredToken => "expired token",
+            ErrorKind::Throttling => "throttling",
+            ErrorKind::Tls => "tls",
+            ErrorKind::Network => "network",
+            ErrorKind::Deadline => "deadline",
+            ErrorKind::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::credentials::Credentials;
+
+use anyhow::Context;
+use anyhow::Result;
+use std::process::ExitStatus;
+use tokio::process::{Child, Command};
+
+pub async fn run(
+    creds: &Credentials,
+    region: &Option<String>,
+    command: &Vec<String>,
+) -> Result<i32> {
+    let (program, args) = command
+        .split_first()
+        .context("Missing command to execute")?;
+
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .env_remove("AWS_PROFILE")
+        .env("AWS_ACCESS_KEY_ID", &creds.aws_access_key)
+        .env("AWS_SECRET_ACCESS_KEY", &creds.aws_secret_key)
+        .env("AWS_SESSION_TOKEN", &creds.aws_sts_token);
+    if let Some(expiration) = creds.aws_expiration {
+        cmd.env("AWS_SESSION_EXPIRATION", expiration.to_rfc3339());
+    }
+    if let Some(region) = region {
+        cmd.env("AWS_REGION", region)
+            .env("AWS_DEFAULT_REGION", region);
+    }
+
+    debug!("Executing {:?}", command);
+    let child = cmd
+        .spawn()
+        .with_context(|| format!("Failed executing {}", program))?;
+    let status = wait_forwarding_signals(child).await?;
+
+    Ok(exit_code(status))
+}
+
+#[cfg(unix)]
+async fn wait_forwarding_signals(child: Child) -> Result<ExitStatus> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let pid = child.id() as libc::pid_t;
+    // the child shares our process group so it already gets the interrupt from
+    // the terminal, we only listen to it to stay alive until the child exits
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::pin!(child);
+
+    loop {
+        let sig = tokio::select! {
+            status = &mut child => return Ok(status?),
+            _ = interrupt.recv() => continue,
+            _ = terminate.recv() => libc::SIGTERM,
+            _ = hangup.recv() => libc::SIGHUP,
+        };
+        debug!("Forwarding signal {} to process {}", sig, pid);
+        unsafe {
+            libc::kill(pid, sig);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_forwarding_signals(child: Child) -> Result<ExitStatus> {
+    Ok(child.await?)
+}
+
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
@@ -0,0 +1,213 @@
+use crate::config;
+use crate::credentials::Credentials;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialProcessOutput<'a> {
+    version: u8,
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    session_token: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<String>,
+}
+
+pub fn to_env(creds: &Credentials, region: &Option<String>) -> String {
+    let mut lines = vec![
+        format!("export AWS_ACCESS_KEY_ID={}", creds.aws_access_key),
+        format!("export AWS_SECRET_ACCESS_KEY={}", creds.aws_secret_key),
+        format!("export AWS_SESSION_TOKEN={}", creds.aws_sts_token),
+    ];
+    if let Some(expiration) = creds.aws_expiration {
+        lines.push(format!(
+            "export AWS_SESSION_EXPIRATION={}",
+            expiration.to_rfc3339()
+        ));
+    }
+    if let Some(region) = region {
+        lines.push(format!("export AWS_REGION={}", region));
+    }
+    lines.join("\n")
+}
+
+/// Output format expected by the `credential_process` setting of the AWS CLI and SDKs
+pub fn to_credential_process(creds: &Credentials) -> Result<String> {
+    let output = CredentialProcessOutput {
+        version: 1,
+        access_key_id: &creds.aws_access_key,
+        secret_access_key: &creds.aws_secret_key,
+        session_token: &creds.aws_sts_token,
+        expiration: creds.aws_expiration.map(|e| e.to_rfc3339()),
+    };
+    Ok(serde_json::to_string_pretty(&output)?)
+}
+
+/// Credentials file with the profile holding the credentials, a profile of the same
+/// name is only replaced when it was written by a previous export
+fn replace_profile(data: &str, creds: &Credentials, profile: &str) -> Result<String> {
+    let profile_header = format!("[{}]", profile);
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_profile = false;
+    let mut found = false;
+    let mut exported = false;
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_profile = trimmed == profile_header;
+            found |= in_profile;
+        }
+        if in_profile {
+            exported |= trimmed
+                .splitn(2, '=')
+                .next()
+                .map_or(false, |k| k.trim() == config::EXPORTED_PROFILE_KEY);
+        } else {
+            lines.push(line.to_owned());
+        }
+    }
+    if found && !exported {
+        return Err(anyhow!(
+            "The profile {} was not written by the export command, choose another profile",
+            profile
+        ));
+    }
+    while lines.last().map_or(false, |l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    if !lines.is_empty() {
+        lines.push(String::new());
+    }
+
+    lines.push(profile_header);
+    lines.push(format!("aws_access_key_id = {}", creds.aws_access_key));
+    lines.push(format!("aws_secret_access_key = {}", creds.aws_secret_key));
+    lines.push(format!("aws_session_token = {}", creds.aws_sts_token));
+    if let Some(expiration) = creds.aws_expiration {
+        lines.push(format!("aws_expiration = {}", expiration.to_rfc3339()));
+    }
+    lines.push(format!("{} = true", config::EXPORTED_PROFILE_KEY));
+    lines.push(String::new());
+    Ok(lines.join("\n"))
+}
+
+/// Credentials file only readable by the user, like the AWS CLI creates it
+#[cfg(unix)]
+fn create_credentials_file(p: &Path) -> std::io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(p)
+}
+
+#[cfg(not(unix))]
+fn create_credentials_file(p: &Path) -> std::io::Result<File> {
+    File::create(p)
+}
+
+/// Writes the credentials in the given profile of the aws credentials file,
+/// replacing the profile if a previous export wrote it
+pub fn write_profile(creds: &Credentials, profile: &str) -> Result<PathBuf> {
+    let p = config::aws_credentials_path()?;
+
+    let mut data = String::new();
+    let exists = p.exists();
+    if exists {
+        File::open(&p)
+            .with_context(|| format!("could not read {:?}", p))?
+            .read_to_string(&mut data)?;
+    } else if let Some(parent) = p.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let updated = replace_profile(&data, creds, profile)?;
+    let mut f = if exists {
+        File::create(&p)
+    } else {
+        create_credentials_file(&p)
+    }
+    .with_context(|| format!("could not write {:?}", p))?;
+    f.write_all(updated.as_bytes())?;
+    Ok(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds() -> Credentials {
+        Credentials {
+            aws_access_key: "ASIANEW".to_owned(),
+            aws_secret_key: "new-secret".to_owned(),
+            aws_sts_token: "new-token".to_owned(),
+            aws_expiration: None,
+        }
+    }
+
+    #[test]
+    fn replaces_an_exported_profile() {
+        let data = "\
+[default]
+aws_access_key_id = AKIALONG
+aws_secret_access_key = long-secret
+
+[dev]
+aws_access_key_id = ASIAOLD
+aws_secret_access_key = old-secret
+aws_session_token = old-token
+exported_by_aws_list_ecs_images = true
+
+[other]
+region = eu-west-1
+";
+        assert_eq!(
+            replace_profile(data, &creds(), "dev").unwrap(),
+            "\
+[default]
+aws_access_key_id = AKIALONG
+aws_secret_access_key = long-secret
+
+[other]
+region = eu-west-1
+
+[dev]
+aws_access_key_id = ASIANEW
+aws_secret_access_key = new-secret
+aws_session_token = new-token
+exported_by_aws_list_ecs_images = true
+"
+        );
+    }
+
+    #[test]
+    fn adds_a_new_profile() {
+        assert_eq!(
+            replace_profile("", &creds(), "dev").unwrap(),
+            "\
+[dev]
+aws_access_key_id = ASIANEW
+aws_secret_access_key = new-secret
+aws_session_token = new-token
+exported_by_aws_list_ecs_images = true
+"
+        );
+    }
+
+    #[test]
+    fn refuses_to_replace_a_profile_not_exported() {
+        let data = "[default]\naws_access_key_id = AKIALONG\naws_secret_access_key = long-secret\n";
+        assert!(replace_profile(data, &creds(), "default").is_err());
+        assert!(replace_profile(data, &creds(), "dev").is_ok());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::client::HttpClient;
+use crate::config;
+use crate::config::{AccessKeyCreation, Config};
+use crate::credentials;
+use crate::region;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::prelude::*;
+use console::style;
+use rusoto_credential::StaticProvider;
+use rusoto_iam::{
+    AccessKey, CreateAccessKeyRequest, DeleteAccessKeyRequest, Iam, IamClient,
+    ListAccessKeysRequest, UpdateAccessKeyRequest,
+};
+use rusoto_sts::{GetCallerIdentityRequest, Sts, StsClient};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_ACCESS_KEY_MAX_AGE_DAYS: i64 = 90;
+
+/// A new access key takes a few seconds before being accepted by sts
+const NEW_KEY_CHECK_ATTEMPTS: u32 = 10;
+const NEW_KEY_CHECK_DELAY: Duration = Duration::from_secs(3);
+
+fn build_iam_client(config: &Config, client: Arc<HttpClient>) -> Result<IamClient> {
+    let session_creds = credentials::session_credentials(config)?;
+    let cred_provider = StaticProvider::new(
+        session_creds.aws_access_key,
+        session_creds.aws_secret_key,
+        Some(session_creds.aws_sts_token),
+        None,
+    );
+    Ok(IamClient::new_with(
+        client,
+        cred_provider,
+        region::iam_region(config),
+    ))
+}
+
+async fn get_access_key_creation(
+    iam_client: &IamClient,
+    access_key_id: &str,
+) -> Result<AccessKeyCreation> {
+    let list_access_keys_res = iam_client
+        .list_access_keys(ListAccessKeysRequest::default())
+        .await
+        .context("Failed listing access keys")?;
+
+    let create_date = list_access_keys_res
+        .access_key_metadata
+        .into_iter()
+        .find(|k| k.access_key_id.as_deref() == Some(access_key_id))
+        .and_then(|k| k.create_date)
+        .ok_or(anyhow!("Access key {} not found", access_key_id))?;
+
+    Ok(AccessKeyCreation {
+        access_key_id: access_key_id.to_owned(),
+        create_date: DateTime::parse_from_rfc3339(&create_date)
+            .context("Invalid access key creation date")?,
+    })
+}
+
+/// Warns when the access key is older than the configured maximum age,
+/// its creation date is only fetched once per key
+pub async fn check_access_key_age(config: &mut Config, client: Arc<HttpClient>) {
+    let known = config
+        .aws_access_key_creation
+        .as_ref()
+        .map_or(false, |c| c.access_key_id == config.aws_access_key_id);
+    if !known {
+        let creation_res = match build_iam_client(config, client) {
+            Ok(iam_client) => get_access_key_creation(&iam_client, &config.aws_access_key_id).await,
+            Err(e) => Err(e),
+        };
+        match creation_res {
+            Ok(creation) => {
+                config.aws_access_key_creation = Some(creation);
+                if let Err(e) = config.persist() {
+                    warn!("Could not persist the access key creation date: {:#}", e);
+                }
+            }
+            Err(e) => {
+                debug!("Could not get the access key creation date: {:#}", e);
+                return;
+            }
+        }
+    }
+
+    if let Some(creation) = &config.aws_access_key_creation {
+        let max_age_days = config
+            .access_key_max_age_days
+            .unwrap_or(DEFAULT_ACCESS_KEY_MAX_AGE_DAYS);
+        let age_days = (Utc::now().timestamp() - creation.create_date.timestamp()) / 86400;
+        if age_days > max_age_days {
+            eprintln!(
+                "{}",
+                style(format!(
+                    "Access key {} is {} days old, please rotate it with the rotate-keys command",
+                    creation.access_key_id, age_days
+                ))
+                .yellow()
+            );
+        }
+    }
+}
+
+async fn check_new_access_key(
+    config: &Config,
+    client: Arc<HttpClient>,
+    access_key_id: &str,
+    secret_access_key: &str,
+) -> Result<()> {
+    let cred_provider = StaticProvider::new(
+        access_key_id.to_owned(),
+        secret_access_key.to_owned(),
+        None,
+        None,
+    );
+    let sts_client = StsClient::new_with(client, cred_provider, region::sts_region(config));
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match sts_client
+            .get_caller_identity(GetCallerIdentityRequest {})
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= NEW_KEY_CHECK_ATTEMPTS => {
+                return Err(e).context("New access key is not accepted");
+            }
+            Err(e) => {
+                debug!("New access key not accepted yet: {}", e);
+                tokio::time::delay_for(NEW_KEY_CHECK_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Checks the new access key and stores it where the next runs read it
+async fn store_new_access_key(
+    config: &mut Config,
+    client: Arc<HttpClient>,
+    access_key: &AccessKey,
+) -> Result<()> {
+    let create_date = match &access_key.create_date {
+        Some(d) => DateTime::parse_from_rfc3339(d).context("Invalid access key creation date")?,
+        None => Utc::now().with_timezone(&FixedOffset::east(0)),
+    };
+    check_new_access_key(
+        config,
+        client,
+        &access_key.access_key_id,
+        &access_key.secret_access_key,
+    )
+    .await?;
+
+    if config.aws_use_default_credentials {
+        config::update_default_profile_keys(
+            &access_key.access_key_id,
+            &access_key.secret_access_key,
+        )?;
+    }
+    config.aws_access_key_id = access_key.access_key_id.clone();
+    config.aws_secret_access_key = access_key.secret_access_key.clone();
+    config.aws_access_key_creation = Some(AccessKeyCreation {
+        access_key_id: access_key.access_key_id.clone(),
+        create_date,
+    });
+    match config.persist() {
+        // the keys are read from the credentials file, only the creation date is lost
+        Err(e) if config.aws_use_default_credentials => {
+            warn!("Could not persist the access key creation date: {:#}", e);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Deletes the new access key that could not be stored, an unused second key
+/// would make the next rotations fail
+async fn delete_new_access_key(
+    iam_client: &IamClient,
+    access_key_id: &str,
+    e: anyhow::Error,
+) -> anyhow::Error {
+    match iam_client
+        .delete_access_key(DeleteAccessKeyRequest {
+            access_key_id: access_key_id.to_owned(),
+            user_name: None,
+        })
+        .await
+    {
+        Ok(_) => {
+            println!("Deleted the new access key {}", access_key_id);
+            e
+        }
+        Err(delete_err) => e.context(format!(
+            "The new access key {} could not be deleted ({}), delete it before the next rotation",
+            access_key_id, delete_err
+        )),
+    }
+}
+
+/// Replaces the access key of the user by a new one, the old key is only
+/// deleted once the new one is stored and accepted by aws
+pub async fn rotate_access_key(config: &mut Config, client: Arc<HttpClient>) -> Result<()> {
+    let iam_client = build_iam_client(config, client.clone())?;
+    let old_access_key_id = config.aws_access_key_id.clone();
+
+    let list_access_keys_res = iam_client
+        .list_access_keys(ListAccessKeysRequest::default())
+        .await
+        .context("Failed listing access keys")?;
+    if list_access_keys_res.access_key_metadata.len() > 1 {
+        return Err(anyhow!(
+            "The user already has {} access keys, delete the unused one first",
+            list_access_keys_res.access_key_metadata.len()
+        ));
+    }
+
+    let access_key = iam_client
+        .create_access_key(CreateAccessKeyRequest::default())
+        .await
+        .context("Failed creating access key")?
+        .access_key;
+    println!("Created access key {}", access_key.access_key_id);
+
+    if let Err(e) = store_new_access_key(config, client, &access_key).await {
+        return Err(delete_new_access_key(&iam_client, &access_key.access_key_id, e).await);
+    }
+
+    iam_client
+        .update_access_key(UpdateAccessKeyRequest {
+            access_key_id: old_access_key_id.clone(),
+            status: "Inactive".to_owned(),
+            user_name: None,
+        })
+        .await
+        .with_context(|| format!("Failed deactivating access key {}", old_access_key_id))?;
+    iam_client
+        .delete_access_key(DeleteAccessKeyRequest {
+            access_key_id: old_access_key_id.clone(),
+            user_name: None,
+        })
+        .await
+        .with_context(|| format!("Failed deleting access key {}", old_access_key_id))?;
+    println!("Deleted access key {}", old_access_key_id);
+
+    Ok(())
+}
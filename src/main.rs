@@ -4,34 +4,126 @@ extern crate log;
 #[macro_use]
 extern crate anyhow;
 
+mod cache;
 mod client;
 mod config;
 mod credentials;
 mod ecs;
+mod errors;
+mod exec;
+mod export;
+mod keys;
+mod pac;
+mod recording;
+mod region;
+mod retry;
+mod server;
+mod socks;
+mod stats;
+mod tls;
 
+use anyhow::Context;
 use anyhow::Result;
 use clap::Clap;
 use config::Config;
-use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
+use chrono::prelude::*;
 use console::style;
 use futures::future::join_all;
-use rusoto_ecs::EcsClient;
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 
 use ecs::Image;
+use errors::ErrorKind;
+
+const DEFAULT_CONCURRENCY: usize = 32;
+const DEFAULT_ACCOUNT_CONCURRENCY: usize = 8;
+
+/// Exit code when some roles or clusters could not be scanned
+const PARTIAL_SUCCESS_EXIT_CODE: i32 = 3;
 
 #[derive(Clap)]
 #[clap(version = "0.1")]
 struct CliOpts {
-    #[clap(short = "r", long = "role")]
+    #[clap(short = "r", long = "role", global = true)]
     roles: Vec<String>,
 
-    #[clap(short = "a", long = "role_arn")]
+    #[clap(short = "a", long = "role_arn", global = true)]
     role_arns: Vec<String>,
 
     #[clap(short = "c", long = "cluster_include")]
     cluster_includes: Vec<String>,
+
+    /// Maximum number of ECS requests in flight
+    #[clap(long = "concurrency")]
+    concurrency: Option<usize>,
+
+    /// Maximum number of ECS requests in flight for each account
+    #[clap(long = "account_concurrency")]
+    account_concurrency: Option<usize>,
+
+    /// Prints the api calls, their latencies and the scan time of each role at the end
+    #[clap(long = "stats")]
+    stats: bool,
+
+    /// Seconds after which the scan stops and prints what it got so far
+    #[clap(long = "deadline")]
+    deadline: Option<u64>,
+
+    /// Endpoint url used for all the aws services instead of the real ones (LocalStack, fakes)
+    #[clap(long = "endpoint_url", global = true)]
+    endpoint_url: Option<String>,
+
+    /// Directory where the sanitized aws requests and responses are saved
+    #[clap(long = "record", global = true, conflicts_with = "replay")]
+    record: Option<String>,
+
+    /// Directory of a recording answering the aws requests instead of the network
+    #[clap(long = "replay", global = true)]
+    replay: Option<String>,
+
+    /// Proxy url overriding the configuration and the proxy environment variables
+    #[clap(long = "proxy", global = true)]
+    proxy: Option<String>,
+
+    #[clap(subcommand)]
+    cmd: Option<SubCommand>,
+}
+
+#[derive(Clap)]
+enum SubCommand {
+    /// Prints the identity behind the MFA session and each role
+    Whoami,
+    /// Prints or stores the credentials of the MFA session or of a role for other tools
+    Export(ExportOpts),
+    /// Executes a command with the credentials of the MFA session or of a role
+    Exec(ExecOpts),
+    /// Serves the credentials of the MFA session or of a role as an ECS container credentials endpoint
+    ServeCredentials(ServeCredentialsOpts),
+    /// Lists the latest task definition revisions of a service
+    History(HistoryOpts),
+    /// Replaces the access key of the user by a new one
+    RotateKeys,
+}
+
+#[derive(Clap)]
+struct ExportOpts {
+    #[clap(
+        short = "f",
+        long = "format",
+        default_value = "env",
+        possible_values = &["env", "credential_process", "profile"]
+    )]
+    format: String,
+
+    /// Profile of the aws credentials file to write with the profile format
+    #[clap(short = "p", long = "profile")]
+    profile: Option<String>,
 }
 
 fn get_cluster_short_name(cluster: &String) -> String {
@@ -58,38 +150,452 @@ fn get_image_short_name(image: &Image) -> String {
     )
 }
 
-fn print_results(
-    all_clusters_images: &Vec<HashMap<String, Vec<Image>>>,
-    roles: &Vec<String>,
+fn get_role_short_name<'a>(role_arn: &'a String, config: &'a Config) -> &'a String {
+    match config.roles.get(role_arn) {
+        Some(r) => r,
+        None => role_arn,
+    }
+}
+
+fn print_role_results(
+    role: &String,
+    clusters_images_res: &Result<ecs::ClustersImages>,
     config: &Config,
 ) {
-    for (idx, clusters_images) in all_clusters_images.iter().enumerate() {
-        let role = roles.get(idx).unwrap();
-        let role_short_name = match config.roles.get(role) {
-            Some(r) => r,
-            None => role,
-        };
-        println!("{}:", style(role_short_name).cyan());
-        for (cluster, images) in clusters_images {
-            println!("  {}:", style(get_cluster_short_name(cluster)).green());
-            let mut short_images: Vec<String> = images.iter().map(get_image_short_name).collect();
-            short_images.sort();
-            for image in short_images {
-                println!("    {}", image);
+    let clusters_images = match clusters_images_res {
+        Ok(clusters_images) => &clusters_images.images,
+        Err(_) => return,
+    };
+    println!("{}:", style(get_role_short_name(role, config)).cyan());
+    for (cluster, images) in clusters_images {
+        println!("  {}:", style(get_cluster_short_name(cluster)).green());
+        let mut sorted_images: Vec<(String, &Image)> = images
+            .iter()
+            .map(|image| (get_image_short_name(image), image))
+            .collect();
+        sorted_images.sort_by(|a, b| a.0.cmp(&b.0));
+        for (short_name, image) in sorted_images {
+            print_image(&short_name, image);
+        }
+    }
+}
+
+/// Prints the image, with the counts and deployments of its service when it is
+/// being rolled out or not at its desired count
+fn print_image(short_name: &str, image: &Image) {
+    let short_name = match &image.workload {
+        ecs::Workload::Service => short_name.to_owned(),
+        ecs::Workload::StandaloneTask(group) => format!(
+            "{} {}",
+            short_name,
+            style(format!("(standalone task {})", group)).dim()
+        ),
+        ecs::Workload::ScheduledRule(rule) => format!(
+            "{} {}",
+            short_name,
+            style(format!("(scheduled by rule {})", rule)).dim()
+        ),
+    };
+    let status = match &image.service_status {
+        Some(status)
+            if status.has_failed_deployment()
+                || status.is_rolling_out()
+                || status.is_below_desired_count() =>
+        {
+            status
+        }
+        _ => {
+            println!("    {}", short_name);
+            return;
+        }
+    };
+
+    let mut flags: Vec<&str> = Vec::new();
+    if status.has_failed_deployment() {
+        flags.push("deployment failed");
+    }
+    if status.is_rolling_out() {
+        flags.push("rolling out");
+    }
+    if status.is_below_desired_count() {
+        flags.push("below desired count");
+    }
+    let summary = format!(
+        "[{}] desired {}, running {}, pending {}",
+        flags.join(", "),
+        status.desired_count,
+        status.running_count,
+        status.pending_count
+    );
+    let summary = if status.has_failed_deployment() {
+        style(summary).red()
+    } else {
+        style(summary).yellow()
+    };
+    println!("    {} {}", short_name, summary);
+    for deployment in &status.deployments {
+        println!(
+            "      {} {} {}: desired {}, running {}, pending {}",
+            deployment.status,
+            deployment.task_definition.split('/').last().unwrap_or(""),
+            deployment.rollout_state.as_deref().unwrap_or("-"),
+            deployment.desired_count,
+            deployment.running_count,
+            deployment.pending_count
+        );
+        if deployment.rollout_state.as_deref() == Some("FAILED") {
+            if let Some(reason) = &deployment.rollout_state_reason {
+                println!("        {}", style(reason).red());
+            }
+        }
+    }
+}
+
+/// Prints the certificates presented for the STS endpoint, the same TLS intercepting proxy
+/// usually stands in front of all the endpoints
+async fn print_tls_diagnostic(config: &Config, proxy_override: Option<&String>) {
+    let sts_host = region::sts_host(config);
+    let chain = client::fetch_certificate_chain(&sts_host, proxy_override, config).await;
+    tls::print_certificate_diagnostic(&sts_host, chain, &config.ca_bundles);
+}
+
+/// Prints the roles and clusters that could not be scanned, returns false when there is none
+async fn print_failures(
+    all_clusters_images: &Vec<(&String, Result<ecs::ClustersImages>)>,
+    config: &Config,
+    proxy_override: Option<&String>,
+) -> bool {
+    let mut failures: Vec<(String, &anyhow::Error)> = Vec::new();
+    for (role, clusters_images_res) in all_clusters_images {
+        let role_short_name = get_role_short_name(role, config);
+        match clusters_images_res {
+            Ok(clusters_images) => {
+                for (cluster, e) in &clusters_images.failures {
+                    failures.push((
+                        format!("{}/{}", role_short_name, get_cluster_short_name(cluster)),
+                        e,
+                    ));
+                }
+            }
+            Err(e) => failures.push((role_short_name.clone(), e)),
+        }
+    }
+    if failures.is_empty() {
+        return false;
+    }
+
+    failures.sort_by(|a, b| a.0.cmp(&b.0));
+    println!("{}:", style("Failures").red());
+    for (location, e) in &failures {
+        println!(
+            "  {} [{}]: {:#}",
+            location,
+            style(ErrorKind::of(e).name()).yellow(),
+            e
+        );
+    }
+    if failures
+        .iter()
+        .any(|(_, e)| ErrorKind::of(e) == ErrorKind::Tls)
+    {
+        print_tls_diagnostic(config, proxy_override).await;
+    }
+    true
+}
+
+fn format_remaining(expiration: &Option<DateTime<FixedOffset>>) -> String {
+    match expiration {
+        Some(x) => {
+            let remaining = x.timestamp() - Utc::now().timestamp();
+            if remaining <= 0 {
+                "expired".to_owned()
+            } else {
+                format!("{}h{:02}m", remaining / 3600, (remaining % 3600) / 60)
             }
         }
+        None => "unknown".to_owned(),
+    }
+}
+
+fn print_identity(
+    name: &str,
+    identity_res: &Result<(credentials::Identity, Option<DateTime<FixedOffset>>)>,
+) {
+    println!("{}:", style(name).cyan());
+    match identity_res {
+        Ok((identity, expiration)) => {
+            println!("  account:    {}", identity.account);
+            println!("  arn:        {}", identity.arn);
+            println!("  user id:    {}", identity.user_id);
+            println!("  expires in: {}", format_remaining(expiration));
+        }
+        Err(e) => println!("  {}", style(format!("{:#}", e)).red()),
+    }
+}
+
+async fn whoami(
+    config: &Config,
+    client: Arc<client::HttpClient>,
+    role_arns: &Vec<String>,
+) -> Result<()> {
+    let session_creds = credentials::session_credentials(config)?;
+    let session_identity_res =
+        credentials::get_caller_identity(config, client.clone(), &session_creds)
+            .await
+            .map(|identity| (identity, session_creds.aws_expiration));
+    print_identity("mfa session", &session_identity_res);
+
+    let get_identities_futures = role_arns.iter().map(|role_arn| {
+        let client = client.clone();
+        async move {
+            let creds = credentials::assume_role(
+                config,
+                client.clone(),
+                role_arn,
+                credentials::SessionScope::Inventory,
+            )
+            .await?;
+            let identity = credentials::get_caller_identity(config, client, &creds).await?;
+            Ok::<_, anyhow::Error>((identity, creds.aws_expiration))
+        }
+    });
+    let get_identities = join_all(get_identities_futures).await;
+
+    for (role_arn, identity_res) in role_arns.iter().zip(get_identities) {
+        print_identity(get_role_short_name(role_arn, config), &identity_res);
+    }
+    Ok(())
+}
+
+#[derive(Clap)]
+struct ExecOpts {
+    /// Command to execute, given after --
+    #[clap(required = true)]
+    command: Vec<String>,
+}
+
+#[derive(Clap)]
+struct ServeCredentialsOpts {
+    #[clap(short = "l", long = "listen", default_value = "127.0.0.1:9911")]
+    listen: String,
+
+    /// Authorization token expected from the clients, generated if not set
+    #[clap(short = "t", long = "token")]
+    token: Option<String>,
+}
+
+#[derive(Clap)]
+struct HistoryOpts {
+    /// Name of the service
+    service: String,
+
+    /// Cluster of the service, searched in all the clusters if not set
+    #[clap(long = "cluster")]
+    cluster: Option<String>,
+
+    /// Number of revisions to list
+    #[clap(short = "n", long = "limit", default_value = "10")]
+    limit: usize,
+}
+
+/// Returns the role explicitly selected on the command line, if any,
+/// commands working with a single set of credentials refuse several roles
+fn get_single_role_arn(opts: &CliOpts, role_arns: &Vec<String>) -> Result<Option<String>> {
+    if opts.roles.is_empty() && opts.role_arns.is_empty() {
+        return Ok(None);
+    }
+    match role_arns.len() {
+        0 => Err(anyhow!("No configured role matches {:?}", opts.roles)),
+        1 => Ok(role_arns.first().cloned()),
+        n => Err(anyhow!("Expected a single role but {} are selected", n)),
+    }
+}
+
+fn concurrency_limits(opts: &CliOpts, config: &Config) -> ecs::ConcurrencyLimits {
+    ecs::ConcurrencyLimits::new(
+        opts.concurrency
+            .or(config.concurrency)
+            .unwrap_or(DEFAULT_CONCURRENCY),
+        opts.account_concurrency
+            .or(config.account_concurrency)
+            .unwrap_or(DEFAULT_ACCOUNT_CONCURRENCY),
+    )
+}
+
+fn task_definition_cache(opts: &CliOpts) -> Arc<cache::TaskDefinitionCache> {
+    // the recordings must hold all the task definitions
+    Arc::new(if opts.record.is_some() || opts.replay.is_some() {
+        cache::TaskDefinitionCache::in_memory()
+    } else {
+        cache::TaskDefinitionCache::load()
+    })
+}
+
+async fn history(
+    opts: &CliOpts,
+    config: &Config,
+    client: Arc<client::HttpClient>,
+    role_arn: &Option<String>,
+    history_opts: &HistoryOpts,
+) -> Result<()> {
+    let creds = match role_arn {
+        Some(role_arn) => {
+            credentials::assume_role(
+                config,
+                client.clone(),
+                role_arn,
+                credentials::SessionScope::Inventory,
+            )
+            .await?
+        }
+        None => credentials::session_credentials(config)?,
+    };
+    let task_definitions = task_definition_cache(opts);
+    let ecs_client = ecs::build_ecs_client(
+        client,
+        creds,
+        region::ecs_region(config)?,
+        region::events_region(config)?,
+        &concurrency_limits(opts, config),
+        task_definitions.clone(),
+    );
+
+    let (cluster, deployed) = ecs::find_service_task_definition(
+        &ecs_client,
+        &history_opts.service,
+        history_opts.cluster.as_ref(),
+    )
+    .await?;
+    let family = ecs::get_task_definition_family(&deployed);
+    let revisions =
+        ecs::get_task_definition_history(&ecs_client, family, history_opts.limit).await?;
+    let revisions_futures = revisions
+        .iter()
+        .map(|arn| ecs::get_task_definition_revision(&ecs_client, arn));
+    let described_revisions: Result<Vec<_>> =
+        join_all(revisions_futures).await.into_iter().collect();
+    if let Err(e) = task_definitions.persist() {
+        warn!("Could not persist the task definition cache: {:#}", e);
+    }
+
+    println!(
+        "{}:",
+        style(format!(
+            "{}/{}",
+            get_cluster_short_name(&cluster),
+            history_opts.service
+        ))
+        .cyan()
+    );
+    for (arn, described_revision) in revisions.iter().zip(described_revisions?) {
+        let revision = arn.rsplit('/').next().unwrap_or(arn);
+        let registered_at = described_revision.registered_at.map_or_else(
+            || "unknown registration date".to_owned(),
+            |at| {
+                format!(
+                    "registered {}",
+                    at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+                )
+            },
+        );
+        if *arn == deployed {
+            println!(
+                "  {} {} {}",
+                style(revision).green(),
+                registered_at,
+                style("(deployed)").green()
+            );
+        } else {
+            println!("  {} {}", revision, registered_at);
+        }
+        for (container, image) in &described_revision.container_images {
+            println!("    {}: {}", container, image);
+        }
+    }
+    if !revisions.contains(&deployed) {
+        println!(
+            "{}",
+            style(format!(
+                "The deployed revision {} is older than the listed ones",
+                deployed.rsplit('/').next().unwrap_or(&deployed)
+            ))
+            .yellow()
+        );
+    }
+    Ok(())
+}
+
+async fn export(
+    config: &Config,
+    client: Arc<client::HttpClient>,
+    role_arn: &Option<String>,
+    export_opts: &ExportOpts,
+) -> Result<()> {
+    let creds = credentials::get_credentials(config, client, role_arn).await?;
+    match export_opts.format.as_str() {
+        "env" => println!("{}", export::to_env(&creds, &config.region)),
+        "credential_process" => println!("{}", export::to_credential_process(&creds)?),
+        _ => {
+            let profile = export_opts
+                .profile
+                .as_ref()
+                .ok_or(anyhow!("--profile is required with the profile format"))?;
+            let p = export::write_profile(&creds, profile)?;
+            eprintln!("Wrote profile {} in {:?}", profile, p);
+        }
+    }
+    Ok(())
+}
+
+fn print_stats(
+    client: &client::HttpClient,
+    role_durations: &mut [(&String, Duration)],
+    config: &Config,
+    total: Duration,
+) {
+    eprintln!("{}", style("Api calls").bold());
+    for line in client.call_stats_report() {
+        eprintln!("  {}", line);
     }
+    eprintln!("{}", style("Scan time per role").bold());
+    role_durations.sort_by(|(_, a), (_, b)| b.cmp(a));
+    for (role_arn, duration) in role_durations.iter() {
+        eprintln!(
+            "  {:<40} {:>7}",
+            get_role_short_name(role_arn, config),
+            stats::format_duration(*duration)
+        );
+    }
+    eprintln!("Total scan time: {}", stats::format_duration(total));
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
-    let opts: CliOpts = CliOpts::parse();
+    let mut opts: CliOpts = CliOpts::parse();
 
     let mut role_arns: Vec<String> = Vec::new();
 
-    let mut config = Config::load(opts.role_arns.is_empty())?;
+    // a replay scans the recorded roles, whatever the local configuration holds
+    let replayed_session = match &opts.replay {
+        Some(dir) => Some(recording::Session::load(dir)?),
+        None => None,
+    };
+    let mut config = match replayed_session {
+        Some(session) => {
+            opts.roles.clear();
+            opts.role_arns = if session.roles_selected {
+                session.roles.keys().cloned().collect()
+            } else {
+                Vec::new()
+            };
+            opts.cluster_includes = session.cluster_includes;
+            Config::for_replay(session.region, session.roles)
+        }
+        None => Config::load(opts.role_arns.is_empty())?,
+    };
+    config.endpoint_url_override = opts.endpoint_url.clone();
     if opts.role_arns.is_empty() {
         let filtered_role_arns: Vec<String> = config
             .roles
@@ -106,38 +612,179 @@ async fn main() -> Result<()> {
     } else {
         role_arns.extend_from_slice(&opts.role_arns);
     }
-    let client = Arc::new(client::new_client()?);
 
-    credentials::update_temp_credentials(&mut config, client.clone()).await?;
+    if let Some(dir) = &opts.record {
+        recording::Session {
+            region: config.region.clone(),
+            roles: role_arns
+                .iter()
+                .map(|role_arn| {
+                    (
+                        role_arn.clone(),
+                        get_role_short_name(role_arn, &config).clone(),
+                    )
+                })
+                .collect(),
+            roles_selected: !opts.roles.is_empty() || !opts.role_arns.is_empty(),
+            cluster_includes: opts.cluster_includes.clone(),
+        }
+        .save(dir)?;
+    }
+
+    let proxy_override = opts.proxy.clone().or_else(|| config.proxy.clone());
+    let diagnostic_config = config.clone();
 
-    let get_creds_futures = role_arns
-        .iter()
-        .map(|role_arn| credentials::assume_role(&config, client.clone(), role_arn));
+    let res = run(&opts, config, role_arns, proxy_override.as_ref()).await;
+    if let Err(e) = &res {
+        if ErrorKind::of(e) == ErrorKind::Tls {
+            print_tls_diagnostic(&diagnostic_config, proxy_override.as_ref()).await;
+        }
+    }
+    res
+}
 
-    let get_creds = join_all(get_creds_futures).await;
+async fn run(
+    opts: &CliOpts,
+    mut config: Config,
+    role_arns: Vec<String>,
+    proxy_override: Option<&String>,
+) -> Result<()> {
+    let recording = match (&opts.record, &opts.replay) {
+        (Some(dir), _) => Some(recording::Mode::Record(dir.clone())),
+        (_, Some(dir)) => Some(recording::Mode::Replay(dir.clone())),
+        _ => None,
+    };
+    let client = Arc::new(client::new_client(
+        proxy_override,
+        recording.as_ref(),
+        &config,
+    )?);
+
+    // a replay must neither prompt for a code nor persist its placeholder session
+    if opts.replay.is_none() {
+        credentials::update_temp_credentials(&mut config, client.clone()).await?;
+
+        if let Some(SubCommand::RotateKeys) = opts.cmd {
+            return keys::rotate_access_key(&mut config, client).await;
+        }
+        keys::check_access_key_age(&mut config, client.clone()).await;
+    } else if let Some(SubCommand::RotateKeys) = opts.cmd {
+        return Err(anyhow!("The access key rotation cannot be replayed"));
+    }
 
-    let all_creds_res: Result<Vec<credentials::Credentials>> =
-        get_creds.into_iter().map(|c| c).collect();
+    match &opts.cmd {
+        Some(SubCommand::Whoami) => return whoami(&config, client, &role_arns).await,
+        Some(SubCommand::Export(export_opts)) => {
+            let role_arn = get_single_role_arn(opts, &role_arns)?;
+            return export(&config, client, &role_arn, export_opts).await;
+        }
+        Some(SubCommand::Exec(exec_opts)) => {
+            let role_arn = get_single_role_arn(opts, &role_arns)?;
+            let creds = credentials::get_credentials(&config, client, &role_arn).await?;
+            let code = exec::run(&creds, &config.region, &exec_opts.command).await?;
+            std::process::exit(code);
+        }
+        Some(SubCommand::ServeCredentials(serve_opts)) => {
+            let role_arn = get_single_role_arn(opts, &role_arns)?;
+            let addr: SocketAddr = serve_opts
+                .listen
+                .parse()
+                .with_context(|| format!("Invalid listen address {}", serve_opts.listen))?;
+            let token = serve_opts.token.clone().unwrap_or_else(|| {
+                thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(32)
+                    .collect::<String>()
+            });
+            eprintln!("AWS_CONTAINER_CREDENTIALS_FULL_URI=http://{}/", addr);
+            eprintln!("AWS_CONTAINER_AUTHORIZATION_TOKEN={}", token);
+            let credentials_server =
+                server::CredentialsServer::new(config, client, role_arn, token);
+            return server::serve(credentials_server, addr).await;
+        }
+        Some(SubCommand::History(history_opts)) => {
+            let role_arn = get_single_role_arn(opts, &role_arns)?;
+            return history(opts, &config, client, &role_arn, history_opts).await;
+        }
+        Some(SubCommand::RotateKeys) | None => {}
+    }
 
-    let ecs_clients: Vec<EcsClient> = all_creds_res?
-        .into_iter()
-        .map(|creds| ecs::build_ecs_client(client.clone(), creds))
+    let ecs_region = region::ecs_region(&config)?;
+    let events_region = region::events_region(&config)?;
+    let limits = concurrency_limits(opts, &config);
+    let deadline = opts
+        .deadline
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let task_definitions = task_definition_cache(opts);
+    let scan_started = Instant::now();
+    let config_ref = &config;
+    let limits_ref = &limits;
+    let cluster_includes = &opts.cluster_includes;
+    let mut scans: FuturesUnordered<_> = role_arns
+        .iter()
+        .map(|role_arn| {
+            let client = client.clone();
+            let ecs_region = ecs_region.clone();
+            let events_region = events_region.clone();
+            let task_definitions = task_definitions.clone();
+            async move {
+                let started = Instant::now();
+                let scan_res = async {
+                    let creds = ecs::until_deadline(
+                        deadline,
+                        credentials::assume_role(
+                            config_ref,
+                            client.clone(),
+                            role_arn,
+                            credentials::SessionScope::Inventory,
+                        ),
+                    )
+                    .await?;
+                    let ecs_client = ecs::build_ecs_client(
+                        client,
+                        creds,
+                        ecs_region,
+                        events_region,
+                        limits_ref,
+                        task_definitions,
+                    );
+                    ecs::get_images_of_clusters(&ecs_client, cluster_includes, deadline).await
+                }
+                .await;
+                (role_arn, scan_res, started.elapsed())
+            }
+        })
         .collect();
 
-    let get_images_of_clusters_results = join_all(
-        ecs_clients
-            .iter()
-            .map(|ecs_client| ecs::get_images_of_clusters(&ecs_client, &opts.cluster_includes)),
-    )
-    .await;
+    // the results of each role are printed as soon as it is scanned
+    let mut all_clusters_images = Vec::new();
+    let mut role_durations = Vec::new();
+    while let Some((role_arn, clusters_images_res, duration)) = scans.next().await {
+        print_role_results(role_arn, &clusters_images_res, &config);
+        all_clusters_images.push((role_arn, clusters_images_res));
+        role_durations.push((role_arn, duration));
+    }
+    if let Err(e) = task_definitions.persist() {
+        warn!("Could not persist the task definition cache: {:#}", e);
+    }
+    if let Some(retry_summary) = client.retry_summary() {
+        eprintln!("{}", style(retry_summary).yellow());
+    }
+    if opts.stats {
+        print_stats(
+            &client,
+            &mut role_durations,
+            &config,
+            scan_started.elapsed(),
+        );
+    }
 
-    let images_of_clusters_res: Result<Vec<HashMap<String, Vec<Image>>>> =
-        get_images_of_clusters_results
-            .into_iter()
-            .map(|ic| ic)
-            .collect();
-    let cluster_images = images_of_clusters_res?;
-    print_results(&cluster_images, &role_arns, &config);
+    if print_failures(&all_clusters_images, &config, proxy_override).await {
+        if all_clusters_images.iter().all(|(_, res)| res.is_err()) {
+            return Err(anyhow!("All the roles failed"));
+        }
+        std::process::exit(PARTIAL_SUCCESS_EXIT_CODE);
+    }
 
     Ok(())
 }
@@ -0,0 +1,811 @@
+use anyhow::Context;
+use anyhow::Result;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Way to reach a host, as returned by FindProxyForURL
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacRoute {
+    Direct,
+    Proxy(String),
+    Socks(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Punct(&'static str),
+}
+
+#[derive(Debug)]
+enum Operand {
+    Str(String),
+    Var(String),
+    /// dnsResolve and myIpAddress
+    Call(String, Vec<Operand>),
+    /// toLowerCase and toUpperCase
+    Method(Box<Operand>, String),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Eq(Operand, Operand),
+    Call(String, Vec<Operand>),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Return(String),
+    Assign(String, Operand),
+}
+
+/// Time given to the proxy of a route before falling back to the next route
+const PROXY_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PUNCTUATIONS: &[&str] = &[
+    "===", "!==", "==", "!=", "&&", "||", "(", ")", "{", "}", ";", ",", "!", "=", ".",
+];
+
+/// PAC functions returning a boolean, usable as conditions
+const FUNCTIONS: &[&str] = &[
+    "shExpMatch",
+    "dnsDomainIs",
+    "isPlainHostName",
+    "localHostOrDomainIs",
+    "isInNet",
+    "isResolvable",
+];
+
+/// PAC functions returning a string
+const VALUE_FUNCTIONS: &[&str] = &["dnsResolve", "myIpAddress"];
+
+const METHODS: &[&str] = &["toLowerCase", "toUpperCase"];
+
+/// Functions resolving names, the PAC files using them are evaluated off the runtime thread
+const DNS_FUNCTIONS: &[&str] = &["isInNet", "isResolvable", "dnsResolve", "myIpAddress"];
+
+const KEYWORDS: &[&str] = &["if", "else", "return", "var", "function"];
+
+fn tokenize(script: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = script;
+    'tokens: while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if rest.starts_with("//") {
+            rest = rest.find('\n').map_or("", |end| &rest[end..]);
+        } else if rest.starts_with("/*") {
+            let end = rest.find("*/").context("Unterminated comment")?;
+            rest = &rest[end + 2..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..].find(c).context("Unterminated string")?;
+            tokens.push(Token::Str(rest[1..end + 1].to_owned()));
+            rest = &rest[end + 2..];
+        } else if c.is_alphanumeric() || c == '_' || c == '$' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or_else(|| rest.len());
+            tokens.push(Token::Ident(rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else {
+            for punct in PUNCTUATIONS {
+                if rest.starts_with(punct) {
+                    tokens.push(Token::Punct(*punct));
+                    rest = &rest[punct.len()..];
+                    continue 'tokens;
+                }
+            }
+            return Err(anyhow!("Unsupported character {:?}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parser of the subset of javascript used by most PAC files: variables and if/else
+/// chains returning string literals, with conditions made of the usual PAC functions
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .context("Unexpected end of the PAC file")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn is_ident(&self, ident: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(i)) if i == ident)
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Ident(i) if !KEYWORDS.contains(&i.as_str()) => Ok(i),
+            token => Err(anyhow!("Expected a name but got {:?}", token)),
+        }
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<()> {
+        match self.next()? {
+            Token::Punct(p) if p == punct => Ok(()),
+            token => Err(anyhow!("Expected {} but got {:?}", punct, token)),
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>> {
+        if self.is_punct("{") {
+            self.next()?;
+            let mut stmts = Vec::new();
+            while !self.is_punct("}") {
+                stmts.push(self.statement()?);
+            }
+            self.next()?;
+            Ok(stmts)
+        } else {
+            Ok(vec![self.statement()?])
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        match self.next()? {
+            Token::Ident(i) if i == "if" => {
+                self.expect_punct("(")?;
+                let condition = self.or()?;
+                self.expect_punct(")")?;
+                let then = self.block()?;
+                let otherwise = if self.is_ident("else") {
+                    self.next()?;
+                    self.block()?
+                } else {
+                    Vec::new()
+                };
+                Ok(Stmt::If(condition, then, otherwise))
+            }
+            Token::Ident(i) if i == "var" => {
+                let name = self.ident()?;
+                let value = if self.is_punct("=") {
+                    self.next()?;
+                    self.operand()?
+                } else {
+                    Operand::Str(String::new())
+                };
+                self.end_of_statement()?;
+                Ok(Stmt::Assign(name, value))
+            }
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) && self.is_punct("=") => {
+                self.next()?;
+                let value = self.operand()?;
+                self.end_of_statement()?;
+                Ok(Stmt::Assign(name, value))
+            }
+            Token::Ident(i) if i == "return" => match self.next()? {
+                Token::Str(s) => {
+                    self.end_of_statement()?;
+                    Ok(Stmt::Return(s))
+                }
+                token => Err(anyhow!(
+                    "Only string literals can be returned, got {:?}",
+                    token
+                )),
+            },
+            token => Err(anyhow!("Unsupported statement starting with {:?}", token)),
+        }
+    }
+
+    fn end_of_statement(&mut self) -> Result<()> {
+        if self.is_punct(";") {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.is_punct("||") {
+            self.next()?;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.is_punct("&&") {
+            self.next()?;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.is_punct("!") {
+            self.next()?;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.is_punct("(") {
+            self.next()?;
+            let expr = self.or()?;
+            self.expect_punct(")")?;
+            return Ok(expr);
+        }
+        if let Some(Token::Ident(name)) = self.peek() {
+            if FUNCTIONS.contains(&name.as_str()) {
+                let name = name.clone();
+                self.next()?;
+                return Ok(Expr::Call(name, self.arguments()?));
+            }
+        }
+        let left = self.operand()?;
+        let negate = match self.next()? {
+            Token::Punct("==") | Token::Punct("===") => false,
+            Token::Punct("!=") | Token::Punct("!==") => true,
+            token => return Err(anyhow!("Unsupported condition near {:?}", token)),
+        };
+        let eq = Expr::Eq(left, self.operand()?);
+        Ok(if negate { Expr::Not(Box::new(eq)) } else { eq })
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Operand>> {
+        self.expect_punct("(")?;
+        let mut args = Vec::new();
+        if !self.is_punct(")") {
+            args.push(self.operand()?);
+            while self.is_punct(",") {
+                self.next()?;
+                args.push(self.operand()?);
+            }
+        }
+        self.expect_punct(")")?;
+        Ok(args)
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        let mut operand = match self.next()? {
+            Token::Str(s) => Operand::Str(s),
+            Token::Ident(i) if VALUE_FUNCTIONS.contains(&i.as_str()) => {
+                Operand::Call(i, self.arguments()?)
+            }
+            Token::Ident(i) if !KEYWORDS.contains(&i.as_str()) => Operand::Var(i),
+            token => return Err(anyhow!("Unsupported operand {:?}", token)),
+        };
+        while self.is_punct(".") {
+            self.next()?;
+            match self.next()? {
+                Token::Ident(method) if METHODS.contains(&method.as_str()) => {
+                    self.expect_punct("(")?;
+                    self.expect_punct(")")?;
+                    operand = Operand::Method(Box::new(operand), method);
+                }
+                token => return Err(anyhow!("Unsupported member {:?}", token)),
+            }
+        }
+        Ok(operand)
+    }
+}
+
+/// shell expression match of PAC files, * and ? wildcards
+fn sh_exp_match(s: &str, pattern: &str) -> bool {
+    let regex = format!(
+        "^{}$",
+        regex::escape(pattern)
+            .replace(r"\*", ".*")
+            .replace(r"\?", ".")
+    );
+    Regex::new(&regex).map_or(false, |r| r.is_match(s))
+}
+
+/// Variables of an evaluation of FindProxyForURL, the names are only resolved
+/// when the evaluation runs off the runtime thread
+struct Scope {
+    vars: HashMap<String, String>,
+    dns: bool,
+}
+
+impl Scope {
+    fn resolve(&self, host: &str) -> Option<Ipv4Addr> {
+        if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            return Some(ip);
+        }
+        if !self.dns {
+            return None;
+        }
+        (host, 0)
+            .to_socket_addrs()
+            .ok()?
+            .find_map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                _ => None,
+            })
+    }
+
+    fn my_ip_address(&self) -> Ipv4Addr {
+        if !self.dns {
+            return Ipv4Addr::LOCALHOST;
+        }
+        // connecting a udp socket sends nothing, it only picks the interface of the default route
+        UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| {
+                socket.connect("8.8.8.8:80")?;
+                socket.local_addr()
+            })
+            .ok()
+            .and_then(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                _ => None,
+            })
+            .unwrap_or(Ipv4Addr::LOCALHOST)
+    }
+
+    fn value(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Str(s) => s.clone(),
+            Operand::Var(name) => self.vars.get(name).cloned().unwrap_or_default(),
+            Operand::Method(operand, method) => match method.as_str() {
+                "toUpperCase" => self.value(operand).to_uppercase(),
+                _ => self.value(operand).to_lowercase(),
+            },
+            Operand::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|a| self.value(a)).collect();
+                match (name.as_str(), args.as_slice()) {
+                    ("dnsResolve", [h]) => {
+                        self.resolve(h).map(|ip| ip.to_string()).unwrap_or_default()
+                    }
+                    ("myIpAddress", []) => self.my_ip_address().to_string(),
+                    _ => {
+                        warn!("Unsupported PAC call {}{:?}", name, args);
+                        String::new()
+                    }
+                }
+            }
+        }
+    }
+
+    fn eval(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Or(a, b) => self.eval(a) || self.eval(b),
+            Expr::And(a, b) => self.eval(a) && self.eval(b),
+            Expr::Not(e) => !self.eval(e),
+            Expr::Eq(a, b) => self.value(a) == self.value(b),
+            Expr::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|a| self.value(a)).collect();
+                let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+                match (name.as_str(), args.as_slice()) {
+                    ("shExpMatch", [s, pattern]) => sh_exp_match(s, pattern),
+                    ("dnsDomainIs", [h, domain]) => h.ends_with(domain),
+                    ("isPlainHostName", [h]) => !h.contains('.'),
+                    ("localHostOrDomainIs", [h, hostdom]) => {
+                        h == hostdom
+                            || (!h.contains('.') && hostdom.starts_with(&format!("{}.", h)))
+                    }
+                    ("isResolvable", [h]) => self.resolve(h).is_some(),
+                    ("isInNet", [h, pattern, mask]) => {
+                        match (
+                            self.resolve(h),
+                            pattern.parse::<Ipv4Addr>(),
+                            mask.parse::<Ipv4Addr>(),
+                        ) {
+                            (Some(ip), Ok(pattern), Ok(mask)) => {
+                                u32::from(ip) & u32::from(mask)
+                                    == u32::from(pattern) & u32::from(mask)
+                            }
+                            _ => false,
+                        }
+                    }
+                    _ => {
+                        warn!("Unsupported PAC call {}{:?}", name, args);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    fn run<'a>(&mut self, stmts: &'a [Stmt]) -> Option<&'a str> {
+        for stmt in stmts {
+            let result = match stmt {
+                Stmt::Return(s) => Some(s.as_str()),
+                Stmt::Assign(name, value) => {
+                    let value = self.value(value);
+                    self.vars.insert(name.clone(), value);
+                    None
+                }
+                Stmt::If(condition, then, otherwise) => {
+                    if self.eval(condition) {
+                        self.run(then)
+                    } else {
+                        self.run(otherwise)
+                    }
+                }
+            };
+            if result.is_some() {
+                return result;
+            }
+        }
+        None
+    }
+}
+
+fn parse_routes(result: &str) -> Vec<PacRoute> {
+    result
+        .split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            match (parts.next()?.to_uppercase().as_str(), parts.next()) {
+                ("DIRECT", _) => Some(PacRoute::Direct),
+                ("PROXY", Some(host_port)) => Some(PacRoute::Proxy(host_port.to_owned())),
+                ("SOCKS", Some(host_port)) | ("SOCKS5", Some(host_port)) => {
+                    Some(PacRoute::Socks(host_port.to_owned()))
+                }
+                (kind, _) => {
+                    debug!("Ignoring unsupported PAC route {}", kind);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parsed FindProxyForURL function of a PAC file, evaluated once per host
+pub struct Pac {
+    url_param: String,
+    host_param: String,
+    body: Vec<Stmt>,
+    uses_dns: bool,
+    routes: Mutex<HashMap<String, Vec<PacRoute>>>,
+}
+
+impl Pac {
+    pub fn load(path: &str) -> Result<Pac> {
+        let script =
+            fs::read_to_string(path).with_context(|| format!("Could not read {}", path))?;
+        Pac::parse(&script).with_context(|| format!("Unsupported PAC file {}", path))
+    }
+
+    fn parse(script: &str) -> Result<Pac> {
+        let tokens = tokenize(script)?;
+        let start = tokens
+            .windows(2)
+            .position(|w| {
+                w[0] == Token::Ident("function".to_owned())
+                    && w[1] == Token::Ident("FindProxyForURL".to_owned())
+            })
+            .context("No FindProxyForURL function")?;
+        let uses_dns = tokens
+            .iter()
+            .any(|t| matches!(t, Token::Ident(i) if DNS_FUNCTIONS.contains(&i.as_str())));
+        let mut parser = Parser {
+            tokens,
+            pos: start + 2,
+        };
+        parser.expect_punct("(")?;
+        let url_param = parser.ident()?;
+        parser.expect_punct(",")?;
+        let host_param = parser.ident()?;
+        parser.expect_punct(")")?;
+        parser.expect_punct("{")?;
+        let mut body = Vec::new();
+        while !parser.is_punct("}") {
+            body.push(parser.statement()?);
+        }
+        Ok(Pac {
+            url_param,
+            host_param,
+            body,
+            uses_dns,
+            routes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn evaluate(&self, host: &str, dns: bool) -> Vec<PacRoute> {
+        let mut scope = Scope {
+            vars: HashMap::new(),
+            dns,
+        };
+        scope
+            .vars
+            .insert(self.url_param.clone(), format!("https://{}/", host));
+        scope.vars.insert(self.host_param.clone(), host.to_owned());
+        let routes = scope.run(&self.body).map(parse_routes).unwrap_or_default();
+        debug!("PAC routes of {}: {:?}", host, routes);
+        routes
+    }
+
+    /// Evaluates the routes of the host before connecting to it, the name resolutions
+    /// of the PAC file block and run off the runtime thread
+    pub async fn prepare(self: Arc<Self>, host: String) {
+        if self.routes.lock().unwrap().contains_key(&host) {
+            return;
+        }
+        let mut routes = if self.uses_dns {
+            let pac = self.clone();
+            let evaluated_host = host.clone();
+            match tokio::task::spawn_blocking(move || pac.evaluate(&evaluated_host, true)).await {
+                Ok(routes) => routes,
+                Err(e) => {
+                    warn!("Could not evaluate the PAC routes of {}: {}", host, e);
+                    Vec::new()
+                }
+            }
+        } else {
+            self.evaluate(&host, false)
+        };
+        if routes.len() > 1 {
+            routes = Pac::reachable_routes(routes).await;
+        }
+        self.routes.lock().unwrap().insert(host, routes);
+    }
+
+    /// Routes starting with the first one whose proxy accepts connections, like the
+    /// browsers falling back to the next route when a proxy is down
+    async fn reachable_routes(mut routes: Vec<PacRoute>) -> Vec<PacRoute> {
+        while routes.len() > 1 {
+            let host_port = match &routes[0] {
+                PacRoute::Direct => break,
+                PacRoute::Proxy(host_port) | PacRoute::Socks(host_port) => host_port.clone(),
+            };
+            match tokio::time::timeout(
+                PROXY_PROBE_TIMEOUT,
+                tokio::net::TcpStream::connect(host_port.as_str()),
+            )
+            .await
+            {
+                Ok(Ok(_)) => break,
+                Ok(Err(e)) => warn!("Skipping the PAC proxy {}: {}", host_port, e),
+                Err(_) => warn!("Skipping the PAC proxy {}: connection timed out", host_port),
+            }
+            routes.remove(0);
+        }
+        routes
+    }
+
+    /// First supported route to the host, direct when the PAC file gives none, the
+    /// prepared hosts skip the proxies that are down, a host that was not prepared
+    /// is evaluated without resolving any name nor trying the proxies
+    pub fn route(&self, host: &str) -> PacRoute {
+        let prepared = self.routes.lock().unwrap().get(host).cloned();
+        let routes = prepared.unwrap_or_else(|| self.evaluate(host, false));
+        routes.first().cloned().unwrap_or(PacRoute::Direct)
+    }
+
+    /// All the http proxies the PAC file can return
+    pub fn http_proxies(&self) -> Vec<String> {
+        let mut proxies = Vec::new();
+        Pac::collect_http_proxies(&self.body, &mut proxies);
+        proxies
+    }
+
+    fn collect_http_proxies(stmts: &[Stmt], proxies: &mut Vec<String>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Return(s) => {
+                    for route in parse_routes(s) {
+                        if let PacRoute::Proxy(host_port) = route {
+                            if !proxies.contains(&host_port) {
+                                proxies.push(host_port);
+                            }
+                        }
+                    }
+                }
+                Stmt::If(_, then, otherwise) => {
+                    Pac::collect_http_proxies(then, proxies);
+                    Pac::collect_http_proxies(otherwise, proxies);
+                }
+                Stmt::Assign(..) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAC_FILE: &str = r#"
+        // proxies of the office network
+        function FindProxyForURL(url, host) {
+            var lower_host = host.toLowerCase();
+            /* internal hosts */
+            if (isPlainHostName(lower_host) || dnsDomainIs(lower_host, ".corp.example.com"))
+                return "DIRECT";
+            if (isInNet(host, "10.0.0.0", "255.0.0.0")) {
+                return 'DIRECT';
+            } else if (shExpMatch(host, "*.amazonaws.com") && !shExpMatch(url, "http:*")) {
+                return "SOCKS5 socks.example.com:1080; DIRECT";
+            }
+            if (host === "sts.amazonaws.com.cn") return "PROXY cn-proxy.example.com:3128";
+            return "PROXY proxy.example.com:8080; DIRECT";
+        }
+    "#;
+
+    #[test]
+    fn tokenizes_strings_comments_and_punctuations() {
+        let tokens = tokenize("if (a === 'b') // comment\n{ x = \"c\"; } /* end */").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("if".to_owned()),
+                Token::Punct("("),
+                Token::Ident("a".to_owned()),
+                Token::Punct("==="),
+                Token::Str("b".to_owned()),
+                Token::Punct(")"),
+                Token::Punct("{"),
+                Token::Ident("x".to_owned()),
+                Token::Punct("="),
+                Token::Str("c".to_owned()),
+                Token::Punct(";"),
+                Token::Punct("}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_tokens() {
+        assert!(tokenize("return \"DIRECT;").is_err());
+        assert!(tokenize("/* comment").is_err());
+        assert!(tokenize("a + b").is_err());
+    }
+
+    #[test]
+    fn matches_shell_expressions() {
+        assert!(sh_exp_match(
+            "ecs.eu-west-1.amazonaws.com",
+            "*.amazonaws.com"
+        ));
+        assert!(!sh_exp_match("amazonaws.com", "*.amazonaws.com"));
+        assert!(sh_exp_match("host1", "host?"));
+        assert!(!sh_exp_match("host12", "host?"));
+        // the dots are not regex wildcards
+        assert!(!sh_exp_match("examplexcom", "example.com"));
+    }
+
+    #[test]
+    fn parses_routes() {
+        assert_eq!(
+            parse_routes("PROXY proxy:8080; SOCKS5 socks:1080;DIRECT"),
+            vec![
+                PacRoute::Proxy("proxy:8080".to_owned()),
+                PacRoute::Socks("socks:1080".to_owned()),
+                PacRoute::Direct,
+            ]
+        );
+        assert_eq!(
+            parse_routes("HTTPS secure:443; socks socks:1080"),
+            vec![PacRoute::Socks("socks:1080".to_owned())]
+        );
+        assert_eq!(parse_routes(""), Vec::new());
+    }
+
+    #[test]
+    fn routes_the_hosts() {
+        let pac = Pac::parse(PAC_FILE).unwrap();
+        assert_eq!(pac.route("intranet"), PacRoute::Direct);
+        assert_eq!(pac.route("Wiki.Corp.Example.com"), PacRoute::Direct);
+        assert_eq!(pac.route("10.1.2.3"), PacRoute::Direct);
+        assert_eq!(
+            pac.route("ecs.eu-west-1.amazonaws.com"),
+            PacRoute::Socks("socks.example.com:1080".to_owned())
+        );
+        assert_eq!(
+            pac.route("sts.amazonaws.com.cn"),
+            PacRoute::Proxy("cn-proxy.example.com:3128".to_owned())
+        );
+        assert_eq!(
+            pac.route("example.org"),
+            PacRoute::Proxy("proxy.example.com:8080".to_owned())
+        );
+        assert_eq!(
+            pac.http_proxies(),
+            vec![
+                "cn-proxy.example.com:3128".to_owned(),
+                "proxy.example.com:8080".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn uses_the_parameter_names_of_the_function() {
+        let pac = Pac::parse(
+            r#"function FindProxyForURL(u, h) {
+                if (localHostOrDomainIs(h, "www.example.com")) return "DIRECT";
+                return "PROXY proxy:8080";
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(pac.route("www"), PacRoute::Direct);
+        assert_eq!(pac.route("www.example.com"), PacRoute::Direct);
+        assert_eq!(
+            pac.route("www.example.org"),
+            PacRoute::Proxy("proxy:8080".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_scripts() {
+        assert!(Pac::parse("function Other(url, host) { return \"DIRECT\"; }").is_err());
+        assert!(Pac::parse(
+            "function FindProxyForURL(url, host) { for (;;) { return \"DIRECT\"; } }"
+        )
+        .is_err());
+        assert!(
+            Pac::parse("function FindProxyForURL(url, host) { return \"PROXY \" + host; }")
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_route_when_a_proxy_is_down() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = listener.local_addr().unwrap().to_string();
+        let down = {
+            let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap().to_string()
+        };
+        let pac = Arc::new(
+            Pac::parse(&format!(
+                r#"function FindProxyForURL(url, host) {{
+                    if (host == "a") return "PROXY {0}; SOCKS {1}; DIRECT";
+                    if (host == "b") return "PROXY {0}; DIRECT";
+                    return "PROXY {1}; PROXY {0}";
+                }}"#,
+                down, up
+            ))
+            .unwrap(),
+        );
+        for host in &["a", "b", "c"] {
+            pac.clone().prepare((*host).to_owned()).await;
+        }
+        assert_eq!(pac.route("a"), PacRoute::Socks(up.clone()));
+        assert_eq!(pac.route("b"), PacRoute::Direct);
+        // the first reachable proxy is kept
+        assert_eq!(pac.route("c"), PacRoute::Proxy(up.clone()));
+        // not prepared, the proxies are not tried
+        assert_eq!(pac.route("d"), PacRoute::Proxy(up.clone()));
+        assert_eq!(pac.http_proxies(), vec![down, up]);
+    }
+
+    #[tokio::test]
+    async fn resolves_names_only_when_prepared() {
+        let pac = Arc::new(
+            Pac::parse(
+                r#"function FindProxyForURL(url, host) {
+                    var ip = dnsResolve(host);
+                    if (isInNet(ip, "127.0.0.0", "255.0.0.0")) return "DIRECT";
+                    return "PROXY proxy:8080";
+                }"#,
+            )
+            .unwrap(),
+        );
+        assert!(pac.uses_dns);
+        // no name resolution on the runtime thread
+        assert_eq!(
+            pac.route("localhost"),
+            PacRoute::Proxy("proxy:8080".to_owned())
+        );
+
+        pac.clone().prepare("localhost".to_owned()).await;
+        assert_eq!(pac.route("localhost"), PacRoute::Direct);
+    }
+}
@@ -0,0 +1,386 @@
+use anyhow::Context;
+use anyhow::Result;
+use hyper::header::HeaderName;
+use hyper::{HeaderMap, StatusCode};
+use regex::Regex;
+use rusoto_core::request::{HttpDispatchError, HttpResponse};
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_core::ByteStream;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const REDACTED: &str = "REDACTED";
+
+/// Response headers that are not needed to replay and could identify the session
+const SKIPPED_RESPONSE_HEADERS: &[&str] = &["set-cookie"];
+
+/// File of the recording directory holding the roles and options of the run
+const SESSION_FILE: &str = "session.json";
+
+/// Recording of the exchanges with the aws apis, chosen on the command line
+pub enum Mode {
+    Record(String),
+    Replay(String),
+}
+
+/// Roles and options of the recorded run, the replay scans the same roles whatever
+/// the local configuration holds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub region: Option<String>,
+    /// Names of the scanned roles by arn
+    pub roles: HashMap<String, String>,
+    /// True when the roles were selected on the command line
+    pub roles_selected: bool,
+    pub cluster_includes: Vec<String>,
+}
+
+impl Session {
+    pub fn save(&self, dir: &str) -> Result<()> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create the recording directory {:?}", dir))?;
+        fs::write(dir.join(SESSION_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(dir: &str) -> Result<Session> {
+        let path = PathBuf::from(dir).join(SESSION_FILE);
+        let json = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read the recorded session {:?}", path))?;
+        Ok(serde_json::from_str(&json)
+            .with_context(|| format!("Invalid recorded session {:?}", path))?)
+    }
+}
+
+/// A request and its response, as saved in the recording directory
+#[derive(Debug, Serialize, Deserialize)]
+struct Exchange {
+    service: String,
+    operation: Option<String>,
+    /// Alias of the access key signing the request, to tell the accounts apart
+    access_key: Option<String>,
+    request: String,
+    status: Option<u16>,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    body: String,
+    error: Option<String>,
+}
+
+/// Service, operation, signing key and body of a request, with the secrets removed
+pub struct ExchangeRequest {
+    service: String,
+    operation: Option<String>,
+    access_key: Option<String>,
+    request: String,
+}
+
+fn request_text(request: &SignedRequest) -> String {
+    match &request.payload {
+        Some(SignedRequestPayload::Buffer(body)) => String::from_utf8_lossy(body).into_owned(),
+        _ => request
+            .params
+            .iter()
+            .map(|(k, v)| match v {
+                Some(v) => format!("{}={}", k, v),
+                None => k.clone(),
+            })
+            .collect::<Vec<String>>()
+            .join("&"),
+    }
+}
+
+fn signing_access_key(request: &SignedRequest) -> Option<String> {
+    let authorization = request
+        .headers
+        .get("authorization")
+        .and_then(|values| values.first())?;
+    let authorization = String::from_utf8_lossy(authorization);
+    let credential = authorization.split("Credential=").nth(1)?;
+    credential.split('/').next().map(|key| key.to_owned())
+}
+
+fn exchange_request(
+    request: &SignedRequest,
+    operation: Option<String>,
+    access_key: Option<String>,
+) -> ExchangeRequest {
+    let mfa_code = Regex::new(r"TokenCode=[^&]*").unwrap();
+    ExchangeRequest {
+        service: request.service.clone(),
+        operation,
+        access_key,
+        request: mfa_code
+            .replace_all(&request_text(request), "TokenCode=REDACTED")
+            .into_owned(),
+    }
+}
+
+/// Saves the exchanges with the aws apis, without the signatures, secret keys,
+/// session tokens, mfa codes and container environment values, the access key ids are replaced by aliases
+pub struct Recorder {
+    dir: PathBuf,
+    count: AtomicUsize,
+    access_key_aliases: Mutex<HashMap<String, String>>,
+}
+
+impl Recorder {
+    pub fn new(dir: &str) -> Result<Recorder> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create the recording directory {:?}", dir))?;
+        Ok(Recorder {
+            dir,
+            count: AtomicUsize::new(0),
+            access_key_aliases: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn access_key_alias(&self, access_key: &str) -> String {
+        let mut aliases = self.access_key_aliases.lock().unwrap();
+        let count = aliases.len();
+        aliases
+            .entry(access_key.to_owned())
+            .or_insert_with(|| format!("AKID{}{:08}", REDACTED, count + 1))
+            .clone()
+    }
+
+    fn sanitize_body(&self, body: &str) -> String {
+        let secrets = Regex::new(r"<(SecretAccessKey|SessionToken)>[^<]*<").unwrap();
+        let body = secrets.replace_all(body, format!("<${{1}}>{}<", REDACTED).as_str());
+        let json_secrets = Regex::new(r#""(SecretAccessKey|SessionToken)"\s*:\s*"[^"]*""#).unwrap();
+        let body = json_secrets.replace_all(&body, format!(r#""${{1}}":"{}""#, REDACTED).as_str());
+        // the scan only needs the names and images of the containers, not their environment
+        let environments =
+            Regex::new(r#""environment"\s*:\s*\[(?:[^\]"]|"(?:[^"\\]|\\.)*")*\]"#).unwrap();
+        let env_values = Regex::new(r#""value"\s*:\s*"(?:[^"\\]|\\.)*""#).unwrap();
+        let body = environments.replace_all(&body, |c: &regex::Captures| {
+            env_values
+                .replace_all(&c[0], format!(r#""value":"{}""#, REDACTED).as_str())
+                .into_owned()
+        });
+        let access_keys = Regex::new(r"<AccessKeyId>([^<]*)</AccessKeyId>").unwrap();
+        access_keys
+            .replace_all(&body, |c: &regex::Captures| {
+                format!(
+                    "<AccessKeyId>{}</AccessKeyId>",
+                    self.access_key_alias(&c[1])
+                )
+            })
+            .into_owned()
+    }
+
+    pub fn request(&self, request: &SignedRequest, operation: Option<String>) -> ExchangeRequest {
+        let access_key = signing_access_key(request).map(|key| self.access_key_alias(&key));
+        exchange_request(request, operation, access_key)
+    }
+
+    /// Saves the exchange and gives the response back, its body is buffered
+    pub async fn save(
+        &self,
+        request: ExchangeRequest,
+        res: Result<HttpResponse, HttpDispatchError>,
+    ) -> Result<HttpResponse, HttpDispatchError> {
+        let buffered = match res {
+            Ok(response) => response.buffer().await,
+            Err(e) => Err(e),
+        };
+        let exchange = match &buffered {
+            Ok(buffered) => Exchange {
+                service: request.service,
+                operation: request.operation,
+                access_key: request.access_key,
+                request: request.request,
+                status: Some(buffered.status.as_u16()),
+                headers: buffered
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| !SKIPPED_RESPONSE_HEADERS.contains(&name.as_str()))
+                    .map(|(name, value)| (name.as_str().to_owned(), value.clone()))
+                    .collect(),
+                body: self.sanitize_body(&String::from_utf8_lossy(&buffered.body)),
+                error: None,
+            },
+            Err(e) => Exchange {
+                service: request.service,
+                operation: request.operation,
+                access_key: request.access_key,
+                request: request.request,
+                status: None,
+                headers: Vec::new(),
+                body: String::new(),
+                error: Some(e.to_string()),
+            },
+        };
+
+        let index = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        let file_name = format!(
+            "{:05}-{}-{}.json",
+            index,
+            exchange.service,
+            exchange.operation.as_deref().unwrap_or("unknown")
+        );
+        let saved = serde_json::to_string_pretty(&exchange)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(fs::write(self.dir.join(&file_name), json)?));
+        if let Err(e) = saved {
+            warn!("Could not record {}: {:#}", file_name, e);
+        }
+
+        let buffered = buffered?;
+        Ok(HttpResponse {
+            status: buffered.status,
+            body: ByteStream::from(buffered.body.to_vec()),
+            headers: buffered.headers,
+        })
+    }
+}
+
+/// Answers the requests from a recording, without any network access
+pub struct Replayer {
+    exchanges: Mutex<Vec<Option<Exchange>>>,
+}
+
+impl Replayer {
+    pub fn new(dir: &str) -> Result<Replayer> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("Could not read the recording directory {}", dir))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+            .filter(|path| path.file_name().map_or(false, |name| name != SESSION_FILE))
+            .collect();
+        // the file names start with the sequence number of the exchange
+        paths.sort();
+
+        let mut exchanges = Vec::new();
+        for path in paths {
+            let json = fs::read_to_string(&path)?;
+            let exchange: Exchange = serde_json::from_str(&json)
+                .with_context(|| format!("Invalid recorded exchange {:?}", path))?;
+            exchanges.push(Some(exchange));
+        }
+        debug!("Replaying {} exchanges from {}", exchanges.len(), dir);
+        Ok(Replayer {
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+
+    /// Takes the first unused exchange of the same operation, preferably signed
+    /// by the same key and with the same body
+    fn take(&self, request: &ExchangeRequest) -> Option<Exchange> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let same_operation =
+            |e: &Exchange| e.service == request.service && e.operation == request.operation;
+        let matchers: [&dyn Fn(&Exchange) -> bool; 3] = [
+            &|e| {
+                same_operation(e)
+                    && e.access_key == request.access_key
+                    && e.request == request.request
+            },
+            &|e| same_operation(e) && e.request == request.request,
+            &|e| same_operation(e),
+        ];
+        for matcher in matchers.iter() {
+            if let Some(slot) = exchanges
+                .iter_mut()
+                .find(|slot| slot.as_ref().map_or(false, |e| matcher(e)))
+            {
+                return slot.take();
+            }
+        }
+        None
+    }
+
+    pub fn answer(
+        &self,
+        request: &SignedRequest,
+        operation: Option<String>,
+    ) -> Result<HttpResponse, HttpDispatchError> {
+        let request = exchange_request(request, operation, signing_access_key(request));
+        let exchange = self.take(&request).ok_or_else(|| {
+            HttpDispatchError::new(format!(
+                "No recorded response left for {} {}",
+                request.service,
+                request.operation.as_deref().unwrap_or("unknown")
+            ))
+        })?;
+
+        if let Some(error) = exchange.error {
+            return Err(HttpDispatchError::new(error));
+        }
+        let status = exchange
+            .status
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| HttpDispatchError::new("Invalid recorded status".to_owned()))?;
+        let mut headers = HeaderMap::<String>::new();
+        for (name, value) in exchange.headers {
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                headers.append(name, value);
+            }
+        }
+        Ok(HttpResponse {
+            status,
+            body: ByteStream::from(exchange.body.into_bytes()),
+            headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::Region;
+
+    fn recorder() -> Recorder {
+        Recorder {
+            dir: PathBuf::new(),
+            count: AtomicUsize::new(0),
+            access_key_aliases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn sanitize_body_removes_the_sts_secrets() {
+        let body = "<Credentials><AccessKeyId>ASIAONE</AccessKeyId>\
+            <SecretAccessKey>secret</SecretAccessKey><SessionToken>token</SessionToken>\
+            </Credentials><AccessKeyId>ASIATWO</AccessKeyId><AccessKeyId>ASIAONE</AccessKeyId>";
+        assert_eq!(
+            recorder().sanitize_body(body),
+            "<Credentials><AccessKeyId>AKIDREDACTED00000001</AccessKeyId>\
+            <SecretAccessKey>REDACTED</SecretAccessKey><SessionToken>REDACTED</SessionToken>\
+            </Credentials><AccessKeyId>AKIDREDACTED00000002</AccessKeyId>\
+            <AccessKeyId>AKIDREDACTED00000001</AccessKeyId>"
+        );
+        assert_eq!(
+            recorder().sanitize_body(r#"{"SecretAccessKey": "secret", "SessionToken":"token"}"#),
+            r#"{"SecretAccessKey":"REDACTED", "SessionToken":"REDACTED"}"#
+        );
+    }
+
+    #[test]
+    fn sanitize_body_removes_the_container_environment_values() {
+        let body = r#"{"containerDefinitions":[{"name":"app","image":"app:1","environment":[{"name":"DB_PASSWORD","value":"p\"a]ss"},{"value":"[1]","name":"LIST"}],"secrets":[]}]}"#;
+        assert_eq!(
+            recorder().sanitize_body(body),
+            r#"{"containerDefinitions":[{"name":"app","image":"app:1","environment":[{"name":"DB_PASSWORD","value":"REDACTED"},{"value":"REDACTED","name":"LIST"}],"secrets":[]}]}"#
+        );
+    }
+
+    #[test]
+    fn exchange_request_removes_the_mfa_code() {
+        let mut request = SignedRequest::new("POST", "sts", &Region::UsEast1, "/");
+        request.add_param("Action", "GetSessionToken");
+        request.add_param("TokenCode", "123456");
+        let exchange = exchange_request(&request, None, Some("AKIDREDACTED00000001".to_owned()));
+        assert_eq!(exchange.service, "sts");
+        assert!(exchange.request.contains("Action=GetSessionToken"));
+        assert!(exchange.request.contains("TokenCode=REDACTED"));
+        assert!(!exchange.request.contains("123456"));
+    }
+}
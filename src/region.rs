@@ -0,0 +1,142 @@
+use crate::config::Config;
+
+use anyhow::Result;
+use hyper::Uri;
+use rusoto_core::region::Region;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub enum Partition {
+    Aws,
+    AwsCn,
+    AwsUsGov,
+}
+
+impl Partition {
+    pub fn of_region(region_name: &str) -> Partition {
+        if region_name.starts_with("cn-") {
+            Partition::AwsCn
+        } else if region_name.starts_with("us-gov-") {
+            Partition::AwsUsGov
+        } else {
+            Partition::Aws
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Partition::Aws => "aws",
+            Partition::AwsCn => "aws-cn",
+            Partition::AwsUsGov => "aws-us-gov",
+        }
+    }
+
+    fn dns_suffix(&self) -> &'static str {
+        match self {
+            Partition::AwsCn => "amazonaws.com.cn",
+            _ => "amazonaws.com",
+        }
+    }
+
+    /// Region hosting the global services like IAM, rusoto would send the GovCloud
+    /// IAM calls to the commercial endpoint
+    fn global_region(&self) -> Region {
+        match self {
+            Partition::Aws => Region::UsEast1,
+            Partition::AwsCn => Region::CnNorth1,
+            Partition::AwsUsGov => Region::Custom {
+                name: Region::UsGovWest1.name().to_owned(),
+                endpoint: "https://iam.us-gov.amazonaws.com".to_owned(),
+            },
+        }
+    }
+}
+
+fn region_name(config: &Config) -> &str {
+    config
+        .region
+        .as_deref()
+        .unwrap_or_else(|| Region::EuWest1.name())
+}
+
+pub fn partition(config: &Config) -> Partition {
+    Partition::of_region(region_name(config))
+}
+
+pub fn region(config: &Config) -> Result<Region> {
+    let name = region_name(config);
+    Region::from_str(name).map_err(|_| anyhow!("Unknown region {}", name))
+}
+
+/// Endpoint overriding the aws one for the service, the command line one first
+fn endpoint_url<'a>(config: &'a Config, service: &str) -> Option<&'a str> {
+    config
+        .endpoint_url_override
+        .as_deref()
+        .or_else(|| config.endpoint_urls.get(service).map(|url| url.as_str()))
+        .or_else(|| config.endpoint_url.as_deref())
+}
+
+/// Custom region sending the requests of the service to its overridden endpoint,
+/// the requests are still signed for the configured region
+fn custom_region(config: &Config, service: &str) -> Option<Region> {
+    endpoint_url(config, service).map(|url| Region::Custom {
+        name: region_name(config).to_owned(),
+        endpoint: url.trim_end_matches('/').to_owned(),
+    })
+}
+
+pub fn sts_host(config: &Config) -> String {
+    let overridden_host = endpoint_url(config, "sts")
+        .and_then(|url| url.parse::<Uri>().ok())
+        .and_then(|uri| uri.host().map(|host| host.to_owned()));
+    overridden_host.unwrap_or_else(|| {
+        format!(
+            "sts.{}.{}",
+            region_name(config),
+            partition(config).dns_suffix()
+        )
+    })
+}
+
+/// Regional STS endpoint, rusoto would otherwise use the global one outside of China
+pub fn sts_region(config: &Config) -> Region {
+    custom_region(config, "sts").unwrap_or_else(|| Region::Custom {
+        name: region_name(config).to_owned(),
+        endpoint: format!("https://{}", sts_host(config)),
+    })
+}
+
+pub fn iam_region(config: &Config) -> Region {
+    custom_region(config, "iam").unwrap_or_else(|| partition(config).global_region())
+}
+
+pub fn ecs_region(config: &Config) -> Result<Region> {
+    match custom_region(config, "ecs") {
+        Some(region) => Ok(region),
+        None => region(config),
+    }
+}
+
+pub fn events_region(config: &Config) -> Result<Region> {
+    match custom_region(config, "events") {
+        Some(region) => Ok(region),
+        None => region(config),
+    }
+}
+
+/// Fails when the arn does not belong to the partition of the configured region
+pub fn check_arn_partition(config: &Config, arn: &str) -> Result<()> {
+    let expected = partition(config);
+    match arn.split(':').nth(1) {
+        Some(arn_partition) if arn_partition == expected.name() => Ok(()),
+        Some(arn_partition) => Err(anyhow!(
+            "{} is in the {} partition but the credentials are for {} ({})",
+            arn,
+            arn_partition,
+            expected.name(),
+            region_name(config)
+        )),
+        None => Err(anyhow!("Invalid arn {}", arn)),
+    }
+}
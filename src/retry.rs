@@ -0,0 +1,76 @@
+use crate::config::Config;
+
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const BASE_DELAY: Duration = Duration::from_millis(200);
+const MAX_DELAY: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryReason {
+    Throttling,
+    ServerError,
+    Network,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config
+                .retry_max_attempts
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+                .max(1),
+        }
+    }
+
+    /// Exponential backoff with full jitter before the given retry, starting at 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exp_delay = BASE_DELAY
+            .checked_mul(1u32 << retry.saturating_sub(1).min(16))
+            .unwrap_or(MAX_DELAY)
+            .min(MAX_DELAY);
+        let jitter_millis = rand::thread_rng().gen_range(0, exp_delay.as_millis() as u64 + 1);
+        Duration::from_millis(jitter_millis)
+    }
+}
+
+/// Counts of the retried requests by reason, shared by all the clients
+#[derive(Debug, Default)]
+pub struct RetryStats {
+    throttling: AtomicUsize,
+    server_error: AtomicUsize,
+    network: AtomicUsize,
+}
+
+impl RetryStats {
+    pub fn record(&self, reason: RetryReason) {
+        let counter = match reason {
+            RetryReason::Throttling => &self.throttling,
+            RetryReason::ServerError => &self.server_error,
+            RetryReason::Network => &self.network,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Human readable summary, none when nothing was retried
+    pub fn summary(&self) -> Option<String> {
+        let throttling = self.throttling.load(Ordering::Relaxed);
+        let server_error = self.server_error.load(Ordering::Relaxed);
+        let network = self.network.load(Ordering::Relaxed);
+        let total = throttling + server_error + network;
+        if total == 0 {
+            return None;
+        }
+        Some(format!(
+            "{} retries ({} throttling, {} server errors, {} network errors)",
+            total, throttling, server_error, network
+        ))
+    }
+}
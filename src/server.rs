@@ -0,0 +1,169 @@
+use crate::client::HttpClient;
+use crate::config::Config;
+use crate::credentials;
+use crate::credentials::Credentials;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::prelude::*;
+use futures::lock::Mutex;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Credentials are refreshed when they expire in less than this delay
+const REFRESH_MARGIN_SECS: i64 = 600;
+
+/// Response format of the ECS container credentials endpoint
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    token: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role_arn: Option<&'a str>,
+}
+
+struct State {
+    config: Config,
+    credentials: Option<Credentials>,
+}
+
+pub struct CredentialsServer {
+    client: Arc<HttpClient>,
+    role_arn: Option<String>,
+    token: String,
+    state: Mutex<State>,
+}
+
+impl CredentialsServer {
+    pub fn new(
+        config: Config,
+        client: Arc<HttpClient>,
+        role_arn: Option<String>,
+        token: String,
+    ) -> CredentialsServer {
+        CredentialsServer {
+            client,
+            role_arn,
+            token,
+            state: Mutex::new(State {
+                config,
+                credentials: None,
+            }),
+        }
+    }
+
+    /// Credentials of the role or of the MFA session, none when the MFA session
+    /// must be renewed with a new code, which is never asked from a request
+    async fn get_credentials(&self) -> Result<Option<Credentials>> {
+        let mut state = self.state.lock().await;
+        if let Some(creds) = &state.credentials {
+            if !needs_refresh(creds) {
+                return Ok(Some(creds.clone()));
+            }
+        }
+
+        if needs_refresh(&credentials::session_credentials(&state.config)?) {
+            // another run of the tool may have renewed the MFA session since the start
+            debug!("Reloading the configuration to find a renewed MFA session");
+            let mut config = Config::load(false)?;
+            config.endpoint_url_override = state.config.endpoint_url_override.take();
+            state.config = config;
+            if needs_refresh(&credentials::session_credentials(&state.config)?) {
+                return Ok(None);
+            }
+        }
+
+        debug!("Refreshing credentials of {:?}", self.role_arn);
+        let creds =
+            credentials::get_credentials(&state.config, self.client.clone(), &self.role_arn)
+                .await?;
+        state.credentials = Some(creds.clone());
+        Ok(Some(creds))
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .map_or(false, |v| v.as_bytes() == self.token.as_bytes());
+        if !authorized {
+            warn!("Rejected request with invalid authorization token");
+            return status_response(StatusCode::UNAUTHORIZED);
+        }
+        if req.method() != Method::GET {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let creds = match self.get_credentials().await {
+            Ok(Some(creds)) => creds,
+            Ok(None) => {
+                warn!(
+                    "The MFA session expires, run the tool in a terminal to enter a new MFA code"
+                );
+                return status_response(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            Err(e) => {
+                error!("Failed getting credentials: {:#}", e);
+                return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let container_creds = ContainerCredentials {
+            access_key_id: &creds.aws_access_key,
+            secret_access_key: &creds.aws_secret_key,
+            token: &creds.aws_sts_token,
+            expiration: creds.aws_expiration.map(|e| e.to_rfc3339()),
+            role_arn: self.role_arn.as_deref(),
+        };
+        match serde_json::to_string(&container_creds) {
+            Ok(body) => Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+            Err(_) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+fn needs_refresh(creds: &Credentials) -> bool {
+    match creds.aws_expiration {
+        Some(x) => x.timestamp() - Utc::now().timestamp() < REFRESH_MARGIN_SECS,
+        None => false,
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn serve(server: CredentialsServer, addr: SocketAddr) -> Result<()> {
+    let server = Arc::new(server);
+
+    let make_svc = make_service_fn(move |_conn| {
+        let server = server.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(req).await) }
+            }))
+        }
+    });
+
+    Server::try_bind(&addr)
+        .with_context(|| format!("Failed listening on {}", addr))?
+        .serve(make_svc)
+        .await
+        .context("Credentials server failed")?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct OperationStats {
+    latencies: Vec<Duration>,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// Calls, latencies and bytes transferred by service and operation, shared by all the clients
+#[derive(Debug, Default)]
+pub struct CallStats {
+    operations: Mutex<BTreeMap<(String, String), OperationStats>>,
+}
+
+/// Nearest rank percentile of sorted latencies
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+impl CallStats {
+    /// Records a call, its latency includes the retries
+    pub fn record(
+        &self,
+        service: &str,
+        operation: &str,
+        latency: Duration,
+        bytes_sent: u64,
+        bytes_received: u64,
+    ) {
+        let mut operations = self.operations.lock().unwrap();
+        let stats = operations
+            .entry((service.to_owned(), operation.to_owned()))
+            .or_default();
+        stats.latencies.push(latency);
+        stats.bytes_sent += bytes_sent;
+        stats.bytes_received += bytes_received;
+    }
+
+    /// One line per service and operation
+    pub fn report(&self) -> Vec<String> {
+        let operations = self.operations.lock().unwrap();
+        operations
+            .iter()
+            .map(|((service, operation), stats)| {
+                let mut sorted = stats.latencies.clone();
+                sorted.sort();
+                format!(
+                    "{:<4} {:<26} {:>5} calls  p50 {:>7}  p90 {:>7}  p99 {:>7}  max {:>7}  sent {:>9}  received {:>9}",
+                    service,
+                    operation,
+                    sorted.len(),
+                    format_duration(percentile(&sorted, 50)),
+                    format_duration(percentile(&sorted, 90)),
+                    format_duration(percentile(&sorted, 99)),
+                    format_duration(sorted[sorted.len() - 1]),
+                    format_bytes(stats.bytes_sent),
+                    format_bytes(stats.bytes_received)
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,434 @@
+use anyhow::Context;
+use anyhow::Result;
+use console::style;
+use native_tls::{Certificate, TlsConnector};
+use std::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Cipher suites of the diagnostic handshake, ECDHE and RSA key exchanges with AES-GCM,
+/// ChaCha20 or AES-CBC, enough for any server or intercepting proxy to answer
+const CIPHER_SUITES: &[u16] = &[
+    0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc009, 0xc013, 0xc00a, 0xc014, 0x009c, 0x009d,
+    0x002f, 0x0035,
+];
+/// x25519, secp256r1 and secp384r1
+const SUPPORTED_GROUPS: &[u16] = &[0x001d, 0x0017, 0x0018];
+/// The rsa and ecdsa signatures with sha256, sha384 and sha512, and rsa-pss
+const SIGNATURE_ALGORITHMS: &[u16] = &[
+    0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0603, 0x0806, 0x0601,
+];
+
+const HANDSHAKE_RECORD: u8 = 22;
+const ALERT_RECORD: u8 = 21;
+const CERTIFICATE_MESSAGE: u8 = 11;
+const SERVER_HELLO_DONE_MESSAGE: u8 = 14;
+/// Largest handshake read before giving up, the certificate chains are a few kilobytes
+const MAX_HANDSHAKE_LEN: usize = 1 << 20;
+
+/// Paths of the CA bundles trusted on top of the system roots
+fn ca_bundle_paths(config_ca_bundles: &Vec<String>) -> Vec<String> {
+    let mut paths = config_ca_bundles.clone();
+    for name in &["AWS_CA_BUNDLE", "SSL_CERT_FILE"] {
+        if let Ok(path) = std::env::var(name) {
+            if !path.trim().is_empty() && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+fn load_pem_certificates(path: &str) -> Result<Vec<Certificate>> {
+    let data = fs::read_to_string(path).with_context(|| format!("could not read {}", path))?;
+
+    let mut certificates = Vec::new();
+    let mut rest = data.as_str();
+    while let Some(begin) = rest.find(PEM_CERTIFICATE_BEGIN) {
+        let end = rest[begin..]
+            .find(PEM_CERTIFICATE_END)
+            .map(|end| begin + end + PEM_CERTIFICATE_END.len())
+            .ok_or(anyhow!("Truncated certificate in {}", path))?;
+        certificates.push(
+            Certificate::from_pem(rest[begin..end].as_bytes())
+                .with_context(|| format!("Invalid certificate in {}", path))?,
+        );
+        rest = &rest[end..];
+    }
+    if certificates.is_empty() {
+        return Err(anyhow!("No PEM certificate found in {}", path));
+    }
+    Ok(certificates)
+}
+
+/// TLS connector trusting the system roots and the configured CA bundles
+pub fn new_tls_connector(config_ca_bundles: &Vec<String>) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+    for path in ca_bundle_paths(config_ca_bundles) {
+        let certificates = load_pem_certificates(&path)?;
+        debug!("Trusting {} certificates of {}", certificates.len(), path);
+        for certificate in certificates {
+            builder.add_root_certificate(certificate);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Reads a DER element, returning its tag, its content and the following bytes
+fn der_read(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.get(0)?;
+    let first_len = *data.get(1)? as usize;
+    let (len, header_len) = if first_len < 0x80 {
+        (first_len, 2)
+    } else {
+        let len_bytes = first_len & 0x7f;
+        if len_bytes > 4 {
+            return None;
+        }
+        let mut len = 0;
+        for i in 0..len_bytes {
+            len = (len << 8) | *data.get(2 + i)? as usize;
+        }
+        (len, 2 + len_bytes)
+    };
+    let content = data.get(header_len..header_len + len)?;
+    Some((tag, content, &data[header_len + len..]))
+}
+
+/// Formats the common attributes of a DER encoded X.509 name
+fn der_name(name: &[u8]) -> String {
+    let mut attributes: Vec<String> = Vec::new();
+    let mut rest = name;
+    while let Some((_, rdn, next)) = der_read(rest) {
+        rest = next;
+        let attribute = der_read(rdn)
+            .and_then(|(_, atv, _)| der_read(atv))
+            .and_then(|(_, oid, value)| der_read(value).map(|(_, value, _)| (oid, value)));
+        if let Some((oid, value)) = attribute {
+            let label = match oid {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => continue,
+            };
+            attributes.push(format!("{}={}", label, String::from_utf8_lossy(value)));
+        }
+    }
+    attributes.join(", ")
+}
+
+/// Subject and issuer of a DER encoded certificate
+fn describe_certificate(der: &[u8]) -> Option<(String, String)> {
+    let (_, certificate, _) = der_read(der)?;
+    let (_, tbs_certificate, _) = der_read(certificate)?;
+    let (tag, _, after_version) = der_read(tbs_certificate)?;
+    // the version is optional and explicitly tagged
+    let serial_and_next = if tag == 0xa0 {
+        after_version
+    } else {
+        tbs_certificate
+    };
+    let (_, _, after_serial) = der_read(serial_and_next)?;
+    let (_, _, after_signature) = der_read(after_serial)?;
+    let (_, issuer, after_issuer) = der_read(after_signature)?;
+    let (_, _, after_validity) = der_read(after_issuer)?;
+    let (_, subject, _) = der_read(after_validity)?;
+    Some((der_name(subject), der_name(issuer)))
+}
+
+fn push_u16_list(data: &mut Vec<u8>, values: &[u16]) {
+    data.extend_from_slice(&((values.len() * 2) as u16).to_be_bytes());
+    for value in values {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn push_extension(data: &mut Vec<u8>, extension_type: u16, content: &[u8]) {
+    data.extend_from_slice(&extension_type.to_be_bytes());
+    data.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    data.extend_from_slice(content);
+}
+
+/// TLS 1.2 ClientHello record, TLS 1.3 is not offered as it encrypts the certificates
+fn client_hello(host: &str, random: [u8; 32]) -> Vec<u8> {
+    let mut server_name = Vec::new();
+    server_name.extend_from_slice(&((host.len() + 3) as u16).to_be_bytes());
+    server_name.push(0);
+    server_name.extend_from_slice(&(host.len() as u16).to_be_bytes());
+    server_name.extend_from_slice(host.as_bytes());
+    let mut supported_groups = Vec::new();
+    push_u16_list(&mut supported_groups, SUPPORTED_GROUPS);
+    let mut signature_algorithms = Vec::new();
+    push_u16_list(&mut signature_algorithms, SIGNATURE_ALGORITHMS);
+
+    let mut extensions = Vec::new();
+    push_extension(&mut extensions, 0x0000, &server_name);
+    push_extension(&mut extensions, 0x000a, &supported_groups);
+    // only uncompressed points
+    push_extension(&mut extensions, 0x000b, &[1, 0]);
+    push_extension(&mut extensions, 0x000d, &signature_algorithms);
+
+    let mut hello = vec![3, 3];
+    hello.extend_from_slice(&random);
+    // no session id
+    hello.push(0);
+    push_u16_list(&mut hello, CIPHER_SUITES);
+    // no compression
+    hello.extend_from_slice(&[1, 0]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend(extensions);
+
+    let mut handshake = vec![1];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend(hello);
+
+    let mut record = vec![HANDSHAKE_RECORD, 3, 1];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend(handshake);
+    record
+}
+
+fn read_u24(data: &[u8]) -> Option<usize> {
+    let bytes = data.get(0..3)?;
+    Some((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+}
+
+/// DER encoded certificates of a Certificate handshake message, the leaf first
+fn parse_certificate_list(message: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let list_len = read_u24(message)?;
+    let mut rest = message.get(3..3 + list_len)?;
+    let mut certificates = Vec::new();
+    while !rest.is_empty() {
+        let len = read_u24(rest)?;
+        certificates.push(rest.get(3..3 + len)?.to_vec());
+        rest = &rest[3 + len..];
+    }
+    Some(certificates)
+}
+
+/// Certificates presented by the server, read from a TLS 1.2 handshake where they are
+/// not encrypted, the handshake is abandoned once they are received
+pub async fn read_certificate_chain<S>(stream: &mut S, host: &str) -> Result<Vec<Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&client_hello(host, rand::random()))
+        .await?;
+
+    let mut handshake: Vec<u8> = Vec::new();
+    loop {
+        let mut header = [0u8; 5];
+        stream
+            .read_exact(&mut header)
+            .await
+            .context("Connection closed during the TLS handshake")?;
+        let mut fragment = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+        stream.read_exact(&mut fragment).await?;
+        match header[0] {
+            HANDSHAKE_RECORD => handshake.extend(fragment),
+            ALERT_RECORD => {
+                return Err(anyhow!(
+                    "TLS handshake refused with alert {}",
+                    fragment.get(1).copied().unwrap_or(0)
+                ))
+            }
+            record_type => return Err(anyhow!("Unexpected TLS record type {}", record_type)),
+        }
+
+        // several messages can share a record and a message can span several records
+        let mut rest = handshake.as_slice();
+        while let (Some(&message_type), Some(len)) =
+            (rest.first(), rest.get(1..).and_then(read_u24))
+        {
+            let message = match rest.get(4..4 + len) {
+                Some(message) => message,
+                None => break,
+            };
+            match message_type {
+                CERTIFICATE_MESSAGE => {
+                    return parse_certificate_list(message)
+                        .context("Invalid TLS certificate message")
+                }
+                SERVER_HELLO_DONE_MESSAGE => return Err(anyhow!("No certificate presented")),
+                _ => rest = &rest[4 + len..],
+            }
+        }
+        if handshake.len() > MAX_HANDSHAKE_LEN {
+            return Err(anyhow!("No certificate presented"));
+        }
+    }
+}
+
+/// Prints the certificates presented for the host, to find out which CA bundle is missing
+/// when the handshake fails behind a TLS intercepting proxy
+pub fn print_certificate_diagnostic(
+    host: &str,
+    chain: Result<Vec<Vec<u8>>>,
+    config_ca_bundles: &Vec<String>,
+) {
+    eprintln!(
+        "{}",
+        style(format!("TLS handshake with {} failed", host)).red()
+    );
+    match chain {
+        Ok(chain) => {
+            eprintln!("  presented certificates:");
+            for (i, der) in chain.iter().enumerate() {
+                match describe_certificate(der) {
+                    Some((subject, issuer)) => {
+                        eprintln!("    {} subject: {}", i, subject);
+                        eprintln!("      issuer:  {}", issuer);
+                    }
+                    None => eprintln!("    {} could not be parsed", i),
+                }
+            }
+        }
+        Err(e) => eprintln!("  could not get the presented certificates: {:#}", e),
+    }
+    let paths = ca_bundle_paths(config_ca_bundles);
+    if paths.is_empty() {
+        eprintln!("  no CA bundle configured, add the issuer of the last certificate to ca_bundles, AWS_CA_BUNDLE or SSL_CERT_FILE");
+    } else {
+        eprintln!("  trusted CA bundles: {}", paths.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Stream answering the recorded records of a server and keeping what was sent to it
+    struct FakeServerStream {
+        replies: io::Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl FakeServerStream {
+        fn new(replies: &[u8]) -> FakeServerStream {
+            FakeServerStream {
+                replies: io::Cursor::new(replies.to_vec()),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncRead for FakeServerStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(io::Read::read(&mut self.replies, buf))
+        }
+    }
+
+    impl AsyncWrite for FakeServerStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.sent.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn record(record_type: u8, fragment: &[u8]) -> Vec<u8> {
+        let mut record = vec![record_type, 3, 3];
+        record.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+        record.extend_from_slice(fragment);
+        record
+    }
+
+    fn message(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![message_type];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn builds_the_client_hello() {
+        let hello = client_hello("sts.amazonaws.com", [7u8; 32]);
+        assert_eq!(&hello[..3], &[HANDSHAKE_RECORD, 3, 1]);
+        assert_eq!(
+            u16::from_be_bytes([hello[3], hello[4]]) as usize,
+            hello.len() - 5
+        );
+        assert_eq!(hello[5], 1);
+        assert_eq!(read_u24(&hello[6..]), Some(hello.len() - 9));
+        assert_eq!(&hello[9..11], &[3, 3]);
+        assert_eq!(&hello[11..43], &[7u8; 32][..]);
+        let sni = b"\x00\x11sts.amazonaws.com";
+        assert!(hello.windows(sni.len()).any(|w| w == sni));
+    }
+
+    #[tokio::test]
+    async fn reads_the_certificate_chain() {
+        let leaf = vec![0x30, 0x01, 0xaa];
+        let intermediate = vec![0x30, 0x02, 0xbb, 0xcc];
+        let mut list = Vec::new();
+        for der in &[&leaf, &intermediate] {
+            list.extend_from_slice(&(der.len() as u32).to_be_bytes()[1..]);
+            list.extend_from_slice(der);
+        }
+        let mut body = (list.len() as u32).to_be_bytes()[1..].to_vec();
+        body.extend(list);
+        let certificate = message(CERTIFICATE_MESSAGE, &body);
+        // the server hello shares its record with the start of the certificate message
+        let mut first = message(2, &[3, 3, 0, 0]);
+        first.extend_from_slice(&certificate[..6]);
+        let replies = [
+            record(HANDSHAKE_RECORD, &first),
+            record(HANDSHAKE_RECORD, &certificate[6..]),
+        ]
+        .concat();
+
+        let mut stream = FakeServerStream::new(&replies);
+        let chain = read_certificate_chain(&mut stream, "sts.amazonaws.com")
+            .await
+            .unwrap();
+        assert_eq!(chain, vec![leaf, intermediate]);
+        assert_eq!(&stream.sent[..3], &[HANDSHAKE_RECORD, 3, 1]);
+    }
+
+    #[tokio::test]
+    async fn reports_the_handshakes_without_certificate() {
+        let mut stream = FakeServerStream::new(&record(ALERT_RECORD, &[2, 40]));
+        let e = read_certificate_chain(&mut stream, "sts.amazonaws.com")
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "TLS handshake refused with alert 40");
+
+        let replies = [
+            record(HANDSHAKE_RECORD, &message(2, &[3, 3])),
+            record(HANDSHAKE_RECORD, &message(SERVER_HELLO_DONE_MESSAGE, &[])),
+        ]
+        .concat();
+        let mut stream = FakeServerStream::new(&replies);
+        let e = read_certificate_chain(&mut stream, "sts.amazonaws.com")
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "No certificate presented");
+
+        let mut stream = FakeServerStream::new(&[]);
+        assert!(read_certificate_chain(&mut stream, "sts.amazonaws.com")
+            .await
+            .is_err());
+    }
+}